@@ -0,0 +1,238 @@
+// Token extraction for the auth filter
+// Each extractor looks in one place a credential may be carried
+
+use serde::{Deserialize, Serialize};
+
+/// Kind of place a token was found in, for logs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenSourceType {
+    Header,
+    Cookie,
+    Query,
+}
+
+impl TokenSourceType {
+    pub fn label(self) -> &'static str {
+        match self {
+            TokenSourceType::Header => "header",
+            TokenSourceType::Cookie => "cookie",
+            TokenSourceType::Query => "query parameter",
+        }
+    }
+}
+
+/// What an extractor found. `token` is None when the carrier is present but malformed, e.g.
+/// an `Authorization` header without the `Bearer` scheme, so the filter can tell a format
+/// error from a missing credential.
+pub struct ExtractedToken {
+    pub token: Option<String>,
+    pub source: TokenSourceType,
+    /// How many times the carrier appeared; only the first is used.
+    pub occurrences: usize,
+}
+
+/// The parts of a request extractors read, kept free of proxy-wasm so they are plain functions
+/// of their input.
+pub struct RequestParts<'a> {
+    pub headers: &'a [(String, String)],
+    pub path: &'a str,
+}
+
+impl RequestParts<'_> {
+    fn header_values<'b>(&'b self, name: &'b str) -> impl Iterator<Item = &'b str> {
+        self.headers.iter()
+            .filter(move |(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub trait TokenExtractor {
+    /// Looks for a token, returning None when this extractor's carrier is absent.
+    fn extract(&self, request: &RequestParts) -> Option<ExtractedToken>;
+}
+
+/// Token in a request header. `Authorization` must use the `Bearer` scheme; other headers,
+/// such as `x-amzn-oidc-accesstoken`, may carry the bare token.
+pub struct HeaderExtractor<'a> {
+    pub name: &'a str,
+}
+
+impl TokenExtractor for HeaderExtractor<'_> {
+    fn extract(&self, request: &RequestParts) -> Option<ExtractedToken> {
+        let values: Vec<&str> = request.header_values(self.name).collect();
+        let value = values.first()?;
+        let token = match value.strip_prefix("Bearer ") {
+            Some(token) => Some(token.to_string()),
+            None if self.name.eq_ignore_ascii_case("authorization") => None,
+            None => Some(value.to_string()),
+        };
+        Some(ExtractedToken { token, source: TokenSourceType::Header, occurrences: values.len() })
+    }
+}
+
+/// Token in the cookie `name`, across all `Cookie` headers.
+pub struct CookieExtractor<'a> {
+    pub name: &'a str,
+}
+
+impl TokenExtractor for CookieExtractor<'_> {
+    fn extract(&self, request: &RequestParts) -> Option<ExtractedToken> {
+        let values: Vec<&str> = request.header_values("cookie")
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter(|(name, _)| *name == self.name)
+            .map(|(_, value)| value.trim_matches('"'))
+            .collect();
+        let value = values.first()?;
+        let token = (!value.is_empty()).then(|| value.to_string());
+        Some(ExtractedToken { token, source: TokenSourceType::Cookie, occurrences: values.len() })
+    }
+}
+
+/// Token in the query parameter `name`, percent-decoded.
+pub struct QueryExtractor<'a> {
+    pub name: &'a str,
+}
+
+impl TokenExtractor for QueryExtractor<'_> {
+    fn extract(&self, request: &RequestParts) -> Option<ExtractedToken> {
+        let (_, query) = request.path.split_once('?')?;
+        let values: Vec<&str> = query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .filter(|(name, _)| *name == self.name)
+            .map(|(_, value)| value)
+            .collect();
+        let value = values.first()?;
+        let token = percent_decode(value).filter(|token| !token.is_empty());
+        Some(ExtractedToken { token, source: TokenSourceType::Query, occurrences: values.len() })
+    }
+}
+
+/// One entry of `token_sources`, e.g. `{"Header": "authorization"}` or `{"Cookie": "session"}`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum TokenSource {
+    Header(String),
+    Cookie(String),
+    Query(String),
+}
+
+impl TokenSource {
+    pub fn name(&self) -> &str {
+        match self {
+            TokenSource::Header(name) | TokenSource::Cookie(name) | TokenSource::Query(name) => name,
+        }
+    }
+
+    pub fn extractor(&self) -> Box<dyn TokenExtractor + '_> {
+        match self {
+            TokenSource::Header(name) => Box::new(HeaderExtractor { name }),
+            TokenSource::Cookie(name) => Box::new(CookieExtractor { name }),
+            TokenSource::Query(name) => Box::new(QueryExtractor { name }),
+        }
+    }
+}
+
+/// Decodes `%XX` escapes; None for malformed escapes (including a sign, which
+/// `from_str_radix` would accept) or non-UTF-8 output. `+` is left alone,
+/// since standard base64 tokens contain it unescaped.
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+                decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn extract(extractor: &dyn TokenExtractor, headers: &[(String, String)], path: &str) -> Option<(Option<String>, usize)> {
+        extractor.extract(&RequestParts { headers, path })
+            .map(|extracted| (extracted.token, extracted.occurrences))
+    }
+
+    #[test]
+    fn header_extractor_requires_bearer_on_authorization() {
+        let extractor = HeaderExtractor { name: "authorization" };
+        let bearer = headers(&[("Authorization", "Bearer abc.def.ghi")]);
+        assert_eq!(extract(&extractor, &bearer, "/"), Some((Some(String::from("abc.def.ghi")), 1)));
+        let basic = headers(&[("authorization", "Basic dXNlcjpwYXNz")]);
+        assert_eq!(extract(&extractor, &basic, "/"), Some((None, 1)));
+        assert_eq!(extract(&extractor, &headers(&[("cookie", "a=b")]), "/"), None);
+    }
+
+    #[test]
+    fn header_extractor_accepts_bare_tokens_elsewhere() {
+        let extractor = HeaderExtractor { name: "x-amzn-oidc-accesstoken" };
+        let bare = headers(&[("x-amzn-oidc-accesstoken", "abc.def.ghi")]);
+        assert_eq!(extract(&extractor, &bare, "/"), Some((Some(String::from("abc.def.ghi")), 1)));
+        let bearer = headers(&[("x-amzn-oidc-accesstoken", "Bearer abc.def.ghi")]);
+        assert_eq!(extract(&extractor, &bearer, "/"), Some((Some(String::from("abc.def.ghi")), 1)));
+    }
+
+    #[test]
+    fn header_extractor_counts_duplicates_and_uses_the_first() {
+        let extractor = HeaderExtractor { name: "authorization" };
+        let duplicated = headers(&[("authorization", "Bearer first"), ("Authorization", "Bearer second")]);
+        assert_eq!(extract(&extractor, &duplicated, "/"), Some((Some(String::from("first")), 2)));
+    }
+
+    #[test]
+    fn cookie_extractor_reads_across_cookie_headers() {
+        let extractor = CookieExtractor { name: "session" };
+        let cookies = headers(&[("cookie", "theme=dark; sessionid=nope"), ("cookie", "session=\"abc.def\"; lang=en")]);
+        assert_eq!(extract(&extractor, &cookies, "/"), Some((Some(String::from("abc.def")), 1)));
+        let empty = headers(&[("cookie", "session=")]);
+        assert_eq!(extract(&extractor, &empty, "/"), Some((None, 1)));
+        let duplicated = headers(&[("cookie", "session=first; session=second")]);
+        assert_eq!(extract(&extractor, &duplicated, "/"), Some((Some(String::from("first")), 2)));
+        assert_eq!(extract(&extractor, &headers(&[("cookie", "theme=dark")]), "/"), None);
+    }
+
+    #[test]
+    fn query_extractor_decodes_the_parameter() {
+        let extractor = QueryExtractor { name: "access_token" };
+        assert_eq!(extract(&extractor, &[], "/ws?room=1&access_token=a%2Eb+c"), Some((Some(String::from("a.b+c")), 1)));
+        assert_eq!(extract(&extractor, &[], "/ws?access_token=first&access_token=second"), Some((Some(String::from("first")), 2)));
+        assert_eq!(extract(&extractor, &[], "/ws?access_token="), Some((None, 1)));
+        assert_eq!(extract(&extractor, &[], "/ws?access_token=bad%zz"), Some((None, 1)));
+        assert_eq!(extract(&extractor, &[], "/ws?my_access_token=x"), None);
+        assert_eq!(extract(&extractor, &[], "/ws"), None);
+    }
+
+    #[test]
+    fn percent_decode_handles_escapes() {
+        assert_eq!(percent_decode("abc").as_deref(), Some("abc"));
+        assert_eq!(percent_decode("a%2Fb%2fc").as_deref(), Some("a/b/c"));
+        assert_eq!(percent_decode("caf%C3%A9").as_deref(), Some("caf\u{e9}"));
+        assert_eq!(percent_decode("a+b").as_deref(), Some("a+b"));
+        assert_eq!(percent_decode("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn percent_decode_rejects_malformed_escapes() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("abc%4"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%-1"), None);
+        assert_eq!(percent_decode("%C3"), None);
+    }
+}
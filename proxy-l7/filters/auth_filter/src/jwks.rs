@@ -0,0 +1,133 @@
+// JWKS key handling for the auth filter
+// Parses JWK sets into `kid`-keyed decoding keys shared by inline, file and remote sources
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// A verification key from a JWK set, with the algorithm the JWK pins it to (if any).
+#[derive(Clone)]
+pub struct JwksKey {
+    pub key: DecodingKey,
+    pub algorithm: Option<Algorithm>,
+}
+
+pub type JwksKeys = HashMap<String, JwksKey>;
+
+/// How tokens from one trusted issuer are verified: a single key pinned to the configured
+/// algorithm, or the issuer's JWK set selected by `kid`, where JWKs that do not pin their own
+/// algorithm are held to the configured one.
+pub enum IssuerKey {
+    Pinned(JwksKey),
+    Jwks { keys: JwksKeys, algorithm: Algorithm },
+}
+
+/// Per-issuer verification keys, by `iss` value.
+pub type IssuerKeys = HashMap<String, IssuerKey>;
+
+/// Builds a decoding key for `algorithm` from an HMAC secret or a PEM public key, whichever the
+/// algorithm family needs.
+pub fn parse_key(algorithm: Algorithm, secret: Option<&str>, public_key: Option<&str>) -> Result<JwksKey, String> {
+    let key = match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = secret.filter(|secret| !secret.is_empty()).ok_or("HMAC algorithms require a secret")?;
+            DecodingKey::from_secret(secret.as_bytes())
+        }
+        _ => {
+            let pem = public_key.ok_or("asymmetric algorithms require a public_key")?.as_bytes();
+            match algorithm {
+                Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+                Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+                _ => DecodingKey::from_rsa_pem(pem),
+            }
+            .map_err(|e| format!("invalid public key: {}", e))?
+        }
+    };
+    Ok(JwksKey { key, algorithm: Some(algorithm) })
+}
+
+/// Parses a JWKS document into keys indexed by `kid`. Keys without a `kid` or with an
+/// unsupported type/algorithm are skipped, but a document with no usable keys is an error.
+pub fn parse_jwks(document: &str) -> Result<JwksKeys, String> {
+    let jwk_set: JwkSet = serde_json::from_str(document).map_err(|e| format!("invalid JWKS document: {}", e))?;
+
+    let mut keys = HashMap::new();
+    for jwk in &jwk_set.keys {
+        let kid = match &jwk.common.key_id {
+            Some(kid) => kid.clone(),
+            None => continue,
+        };
+
+        // Encryption-only algorithms (e.g. RSA-OAEP) don't map to a signing algorithm
+        let algorithm = match &jwk.common.key_algorithm {
+            Some(key_algorithm) => match Algorithm::from_str(&key_algorithm.to_string()) {
+                Ok(algorithm) => Some(algorithm),
+                Err(_) => continue,
+            },
+            None => None,
+        };
+
+        if let Ok(key) = DecodingKey::from_jwk(jwk) {
+            keys.insert(kid, JwksKey { key, algorithm });
+        }
+    }
+
+    if keys.is_empty() {
+        return Err("JWKS document contains no usable signing keys".to_string());
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "c2VjcmV0LW9uZQ" is "secret-one" in unpadded base64url
+    const OCT_KEY: &str = r#"{"kty": "oct", "kid": "hmac-1", "alg": "HS256", "k": "c2VjcmV0LW9uZQ"}"#;
+    const RSA_KEY: &str = r#"{"kty": "RSA", "kid": "rsa-1", "use": "sig", "n": "sXchDaQebHnPiGvyDOAT4saGEUetSyo9MKLOoWFsueri23bOdgWp4Dy1WlUzewbgBHod5pcM9H95GQRV3JDXboIRROSBigeC5yjU1hGzHHyXss8UDprecbAYxknTcQkhslANGRUZmdTOQ5qTRsLAt6BTYuyvVRdhS8exSZEy_c4gs_7svlJJQ4H9_NxsiIoLwAEk7-Q3UXERGYw_75IDrGA84-lA_-Ct4eTlXHBIY2EaV7t7LjJaynVJCpkv4LKjTTAumiGUIuQhrNhZLuF_RJLqHpM2kgWFLU7-VTdL1VbC2tejvcI2BlMkEpk1BzBZI0KQB0GaDWFLN-aEAw3vRw", "e": "AQAB"}"#;
+
+    #[test]
+    fn parses_keys_by_kid() {
+        let keys = parse_jwks(&format!(r#"{{"keys": [{}, {}]}}"#, OCT_KEY, RSA_KEY)).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys["hmac-1"].algorithm, Some(Algorithm::HS256));
+        assert_eq!(keys["rsa-1"].algorithm, None);
+    }
+
+    #[test]
+    fn skips_keys_without_kid() {
+        let document = r#"{"keys": [{"kty": "oct", "alg": "HS256", "k": "c2VjcmV0LXR3bw"}, OCT]}"#.replace("OCT", OCT_KEY);
+        let keys = parse_jwks(&document).unwrap();
+        assert_eq!(keys.keys().collect::<Vec<_>>(), vec!["hmac-1"]);
+    }
+
+    #[test]
+    fn skips_encryption_only_keys() {
+        let encryption_key = RSA_KEY.replace(r#""kid": "rsa-1""#, r#""kid": "enc-1", "alg": "RSA-OAEP""#);
+        let keys = parse_jwks(&format!(r#"{{"keys": [{}, {}]}}"#, encryption_key, OCT_KEY)).unwrap();
+        assert!(!keys.contains_key("enc-1"));
+        assert!(keys.contains_key("hmac-1"));
+    }
+
+    #[test]
+    fn rejects_document_without_usable_keys() {
+        assert!(parse_jwks(r#"{"keys": []}"#).is_err());
+        let encryption_only = RSA_KEY.replace(r#""use": "sig""#, r#""alg": "RSA-OAEP""#);
+        assert!(parse_jwks(&format!(r#"{{"keys": [{}]}}"#, encryption_only)).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_document() {
+        assert!(parse_jwks("not json").is_err());
+        assert!(parse_jwks(r#"{"keys": {}}"#).is_err());
+    }
+
+    #[test]
+    fn parse_key_requires_matching_material() {
+        assert_eq!(parse_key(Algorithm::HS256, Some("secret"), None).unwrap().algorithm, Some(Algorithm::HS256));
+        assert!(parse_key(Algorithm::HS256, Some(""), None).is_err());
+        assert!(parse_key(Algorithm::RS256, Some("secret"), None).is_err());
+        assert!(parse_key(Algorithm::RS256, None, Some("not a pem")).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+// Bounded body buffering for the filters
+// Copies request or response bodies out of the host up to a fixed cap
+
+use proxy_wasm::types::Bytes;
+
+/// Default for each filter's `max_buffer_bytes`: enough for a small JSON document.
+pub fn default_max_buffer_bytes() -> usize {
+    4096
+}
+
+/// Accumulates body chunks up to `max_bytes`. Bytes past the cap are never copied out of the
+/// host, and `truncated` records that the body was cut short, so memory use stays bounded
+/// however large the body is.
+#[derive(Debug, Default)]
+pub struct BodyBuffer {
+    data: Vec<u8>,
+    max_bytes: usize,
+    truncated: bool,
+}
+
+impl BodyBuffer {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { data: Vec::new(), max_bytes, truncated: false }
+    }
+
+    /// Appends a chunk of `chunk_size` bytes; `fetch(len)` must return the first `len` of them,
+    /// e.g. `|len| ctx.get_http_response_body(0, len)` for a callback that returned `Continue`.
+    pub fn append(&mut self, chunk_size: usize, fetch: impl FnOnce(usize) -> Option<Bytes>) {
+        let remaining = self.max_bytes.saturating_sub(self.data.len());
+        let take = chunk_size.min(remaining);
+        if take < chunk_size {
+            self.truncated = true;
+        }
+        if take > 0 {
+            if let Some(chunk) = fetch(take) {
+                self.data.extend_from_slice(&chunk[..take.min(chunk.len())]);
+            }
+        }
+    }
+
+    /// The bytes kept so far, at most `max_bytes`.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// True once the body has grown past `max_bytes`, so `data` holds only its start.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(buffer: &mut BodyBuffer, chunk: &[u8]) {
+        buffer.append(chunk.len(), |len| Some(chunk[..len].to_vec()));
+    }
+
+    #[test]
+    fn body_at_the_limit_is_kept_whole() {
+        let mut buffer = BodyBuffer::new(8);
+        append(&mut buffer, b"{\"a\":");
+        append(&mut buffer, b"1}\n");
+        assert_eq!(buffer.data(), b"{\"a\":1}\n");
+        assert!(!buffer.truncated());
+    }
+
+    #[test]
+    fn one_byte_over_the_limit_truncates() {
+        let mut buffer = BodyBuffer::new(8);
+        append(&mut buffer, b"{\"a\":");
+        append(&mut buffer, b"12}\n");
+        assert_eq!(buffer.data(), b"{\"a\":12}");
+        assert!(buffer.truncated());
+    }
+
+    #[test]
+    fn nothing_is_fetched_once_full() {
+        let mut buffer = BodyBuffer::new(4);
+        append(&mut buffer, b"full");
+        buffer.append(3, |_| panic!("fetched past the cap"));
+        assert_eq!(buffer.data(), b"full");
+        assert!(buffer.truncated());
+    }
+
+    #[test]
+    fn empty_chunks_do_not_truncate() {
+        let mut buffer = BodyBuffer::new(0);
+        buffer.append(0, |_| panic!("fetched an empty chunk"));
+        assert!(buffer.data().is_empty());
+        assert!(!buffer.truncated());
+    }
+}
@@ -0,0 +1,128 @@
+// Helpers shared by the MarchProxy WASM filters
+// Config parsing, filter chain bookkeeping, rejection tagging, counters, authority parsing and
+// bounded body buffering
+
+pub mod body;
+pub mod panic;
+pub mod secrets;
+
+use proxy_wasm::types::*;
+
+/// Filter state listing the MarchProxy filters that have run on this request, in order.
+pub const FILTER_CHAIN_PROPERTY: &str = "marchproxy.filter_chain";
+
+/// Parses plugin configuration as MessagePack when the first byte is a MessagePack map or array
+/// marker (never valid as the start of a JSON document), and as JSON otherwise.
+pub fn parse_config<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    match bytes.first() {
+        Some(0x80..=0x9f | 0xdc..=0xdf) => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        _ => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+    }
+}
+
+/// Appends `filter` to the filter-chain state and, when `expected_position` (1-based) is set,
+/// warns and counts `marchproxy_filter_order_mismatch_by_<filter>` if it runs elsewhere.
+pub fn check_filter_position(filter: &str, expected_position: Option<usize>) {
+    let mut chain: Vec<String> = proxy_wasm::hostcalls::get_property(vec![FILTER_CHAIN_PROPERTY])
+        .ok()
+        .flatten()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .map(|chain| chain.split(',').filter(|name| !name.is_empty()).map(String::from).collect())
+        .unwrap_or_default();
+
+    if let Some(expected) = expected_position {
+        let actual = chain.len() + 1;
+        if actual != expected {
+            proxy_wasm::hostcalls::log(
+                LogLevel::Warn,
+                &format!(
+                    "The {} filter expected at position {} of the MarchProxy filters but runs at {} (after: [{}])",
+                    filter, expected, actual, chain.join(", "),
+                ),
+            ).ok();
+            increment_metric(&format!("marchproxy_filter_order_mismatch_by_{}", filter), 1);
+        }
+    }
+
+    chain.push(filter.to_string());
+    proxy_wasm::hostcalls::set_property(vec![FILTER_CHAIN_PROPERTY], Some(chain.join(",").as_bytes())).ok();
+}
+
+/// Records which filter rejected the request and why, both as
+/// `marchproxy.rejected_by`/`marchproxy.rejection_reason` filter state and as metrics. The
+/// caller sends the response.
+pub fn record_rejection(filter: &str, reason: &str) {
+    proxy_wasm::hostcalls::set_property(vec!["marchproxy.rejected_by"], Some(filter.as_bytes())).ok();
+    proxy_wasm::hostcalls::set_property(vec!["marchproxy.rejection_reason"], Some(reason.as_bytes())).ok();
+    increment_metric("marchproxy_rejections_total", 1);
+    increment_metric(&format!("marchproxy_rejections_by_{}_{}", filter, reason), 1);
+}
+
+/// Adds `value` to the Envoy counter `name`, defining it on first use.
+pub fn increment_metric(name: &str, value: u64) {
+    match proxy_wasm::hostcalls::define_metric(MetricType::Counter, name) {
+        Ok(metric_id) => {
+            proxy_wasm::hostcalls::increment_metric(metric_id, value as i64).ok();
+        }
+        Err(e) => {
+            proxy_wasm::hostcalls::log(LogLevel::Debug, &format!("Failed to define metric {}: {:?}", name, e)).ok();
+        }
+    }
+}
+
+/// Strips the port from an `:authority` value (`host:port` or `[ipv6]:port`). A bare IPv6
+/// address, which has several colons and no port, is returned unchanged.
+pub fn authority_host(authority: &str) -> &str {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => authority,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(default)]
+    struct Sample {
+        name: String,
+        limit: u64,
+    }
+
+    #[test]
+    fn parses_json_config() {
+        let parsed: Sample = parse_config(br#"{"name": "edge", "limit": 5}"#).unwrap();
+        assert_eq!(parsed, Sample { name: String::from("edge"), limit: 5 });
+    }
+
+    #[test]
+    fn parses_messagepack_config() {
+        let sample = Sample { name: String::from("edge"), limit: 5 };
+        let bytes = rmp_serde::to_vec_named(&sample).unwrap();
+        assert_eq!(parse_config::<Sample>(&bytes).unwrap(), sample);
+    }
+
+    #[test]
+    fn rejects_malformed_config() {
+        assert!(parse_config::<Sample>(b"{\"name\": ").is_err());
+    }
+
+    #[test]
+    fn authority_host_strips_port() {
+        assert_eq!(authority_host("api.example.com:8443"), "api.example.com");
+        assert_eq!(authority_host("api.example.com"), "api.example.com");
+        assert_eq!(authority_host("10.0.0.1:80"), "10.0.0.1");
+    }
+
+    #[test]
+    fn authority_host_handles_ipv6() {
+        assert_eq!(authority_host("[::1]:8080"), "::1");
+        assert_eq!(authority_host("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(authority_host("2001:db8::1"), "2001:db8::1");
+    }
+}
@@ -0,0 +1,62 @@
+// Panic reporting for the filters
+// Names the proxy-wasm hook a filter was in when it panicked
+
+use proxy_wasm::types::*;
+use std::cell::Cell;
+
+thread_local! {
+    /// The hook being run, named in panic reports.
+    static CURRENT_HOOK: Cell<&'static str> = const { Cell::new("init") };
+}
+
+/// Replaces the SDK's log-only panic hook. Wasm builds abort on panic, so `catch_unwind` cannot
+/// recover and Envoy tears the VM down, failing the request open or closed per the plugin's
+/// `fail_open` setting. The hook logs the panic with the hook it hit and counts it, so crashes
+/// show up in metrics and not only in logs.
+pub fn install_panic_hook(filter: &'static str) {
+    std::panic::set_hook(Box::new(move |info| {
+        let hook = current_hook();
+        proxy_wasm::hostcalls::log(LogLevel::Critical, &format!("The {} filter panicked in {}: {}", filter, hook, info)).ok();
+        for name in panic_metric_names(filter, hook) {
+            crate::increment_metric(&name, 1);
+        }
+    }));
+}
+
+/// Records the hook about to run for the panic hook.
+pub fn enter_hook(hook: &'static str) {
+    CURRENT_HOOK.with(|current| current.set(hook));
+}
+
+/// The hook most recently entered on this thread.
+pub fn current_hook() -> &'static str {
+    CURRENT_HOOK.with(Cell::get)
+}
+
+/// Counters a panic in `hook` of `filter` increments.
+fn panic_metric_names(filter: &str, hook: &str) -> [String; 2] {
+    [String::from("marchproxy_filter_panics_total"), format!("marchproxy_filter_panics_by_{}_{}", filter, hook)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_report_names_the_hook() {
+        install_panic_hook("test");
+        enter_hook("on_http_request_headers");
+        let result = std::panic::catch_unwind(|| panic!("boom"));
+        let _ = std::panic::take_hook();
+
+        assert!(result.is_err());
+        assert_eq!(current_hook(), "on_http_request_headers");
+        assert_eq!(
+            panic_metric_names("test", current_hook()),
+            [
+                String::from("marchproxy_filter_panics_total"),
+                String::from("marchproxy_filter_panics_by_test_on_http_request_headers"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,106 @@
+// Secret handling shared by the filters
+// Reference resolution, redaction for config dumps and timing-safe comparison
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Resolves `env:NAME` and `sds:/path` references so secrets need not be inlined in config.
+/// Environment variables come from the VM's `environment_variables`, and secret paths must be
+/// mounted into the VM. Any other value is returned unchanged.
+pub fn resolve_secret(value: &str) -> Result<String, String> {
+    if let Some(name) = value.strip_prefix("env:") {
+        std::env::var(name).map_err(|_| format!("environment variable {} is not set", name))
+    } else if let Some(path) = value.strip_prefix("sds:") {
+        std::fs::read_to_string(path)
+            .map(|secret| secret.trim().to_string())
+            .map_err(|e| format!("failed to read secret file {}: {}", path, e))
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+/// Replaces the value of every field named in `fields` with a placeholder, at any depth of a
+/// serialized config.
+pub fn redact_secrets(value: &mut serde_json::Value, fields: &[&str]) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if fields.contains(&key.as_str()) {
+                    if !field.is_null() {
+                        *field = serde_json::Value::from("[redacted]");
+                    }
+                } else {
+                    redact_secrets(field, fields);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| redact_secrets(item, fields)),
+        _ => {}
+    }
+}
+
+/// A short, non-reversible stand-in for a secret in logs: the first 8 hex digits of its SHA-256,
+/// enough to tell which secret is loaded without disclosing it.
+pub fn secret_fingerprint(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())[..4].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compares secrets in constant time, so response timing does not reveal how much of a guess
+/// matched. Only the length is leaked; compare fixed-length digests to hide that too.
+pub fn secret_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    a.as_ref().ct_eq(b.as_ref()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_nested_fields() {
+        let mut config = serde_json::json!({
+            "jwt_secret": "hunter2",
+            "tenants": {"a.example": {"jwt_secret": "s3cret", "header": "x"}},
+            "issuers": [{"secret": "k"}],
+            "config_dump_secret": null,
+        });
+        redact_secrets(&mut config, &["jwt_secret", "secret", "config_dump_secret"]);
+        assert_eq!(config["jwt_secret"], "[redacted]");
+        assert_eq!(config["tenants"]["a.example"]["jwt_secret"], "[redacted]");
+        assert_eq!(config["tenants"]["a.example"]["header"], "x");
+        assert_eq!(config["issuers"][0]["secret"], "[redacted]");
+        assert!(config["config_dump_secret"].is_null());
+    }
+
+    #[test]
+    fn fingerprint_does_not_reveal_secret() {
+        let fingerprint = secret_fingerprint("LIC-1234-5678-ABCD");
+        assert_eq!(fingerprint.len(), 8);
+        assert!(!fingerprint.contains("1234"));
+        assert_eq!(fingerprint, secret_fingerprint("LIC-1234-5678-ABCD"));
+        assert_ne!(fingerprint, secret_fingerprint("LIC-1234-5678-ABCE"));
+    }
+
+    #[test]
+    fn secret_eq_matches_equal_secrets() {
+        assert!(secret_eq("s3cret-token", "s3cret-token"));
+        assert!(secret_eq(b"", b""));
+    }
+
+    #[test]
+    fn secret_eq_rejects_unequal_secrets() {
+        assert!(!secret_eq("s3cret-token", "s3cret-tokeN"));
+        assert!(!secret_eq("s3cret-token", "x3cret-token"));
+    }
+
+    #[test]
+    fn secret_eq_rejects_different_lengths() {
+        assert!(!secret_eq("s3cret-token", "s3cret-token-extra"));
+        assert!(!secret_eq("s3cret", ""));
+    }
+
+    #[test]
+    fn resolves_plain_and_env_secrets() {
+        assert_eq!(resolve_secret("inline").unwrap(), "inline");
+        assert!(resolve_secret("env:MARCHPROXY_TEST_UNSET_SECRET").is_err());
+    }
+}
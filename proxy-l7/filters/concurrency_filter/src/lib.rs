@@ -0,0 +1,341 @@
+// MarchProxy Concurrency Filter (WASM)
+// Sheds load once too many requests are in flight to a route
+
+use marchproxy_filter_common::panic::{enter_hook, install_panic_hook};
+use marchproxy_filter_common::{check_filter_position, parse_config, record_rejection};
+use proxy_wasm::traits::*;
+use proxy_wasm::types::*;
+use serde::{Deserialize, Serialize};
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    install_panic_hook("concurrency");
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(ConcurrencyFilterRoot {
+            config: FilterConfig::default(),
+        })
+    });
+}}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+struct FilterConfig {
+    max_concurrent: Option<u64>,
+    routes: Vec<ConcurrencyRoute>,
+    retry_after_seconds: u64,
+    expected_position: Option<usize>,
+}
+
+/// In-flight cap for requests under `prefix`, counted separately from other routes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ConcurrencyRoute {
+    prefix: String,
+    max_concurrent: u64,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: None,
+            routes: Vec::new(),
+            retry_after_seconds: 1,
+            expected_position: None,
+        }
+    }
+}
+
+/// Attempts at a shared-data compare-and-swap before giving up on taking a slot.
+const CAS_RETRIES: usize = 5;
+
+/// Outcome of a compare-and-swap update of an in-flight counter.
+#[derive(Debug, PartialEq)]
+enum InFlightUpdate {
+    /// The counter now holds this value.
+    Updated(u64),
+    /// The update declined the current count, i.e. the limit is reached.
+    Refused,
+    /// Every attempt lost the race to another worker changing the counter.
+    Contended,
+    /// Shared data rejected the write for a reason retrying will not fix.
+    Failed(Status),
+}
+
+struct ConcurrencyFilterRoot {
+    config: FilterConfig,
+}
+
+impl Context for ConcurrencyFilterRoot {}
+
+impl RootContext for ConcurrencyFilterRoot {
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        enter_hook("on_configure");
+        if let Some(config_bytes) = self.get_plugin_configuration() {
+            match parse_config(&config_bytes) {
+                Ok(config) => {
+                    self.config = config;
+                    proxy_wasm::hostcalls::log(LogLevel::Info, "Concurrency filter configured successfully").ok();
+                    true
+                }
+                Err(e) => {
+                    proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Failed to parse concurrency configuration: {}", e)).ok();
+                    false
+                }
+            }
+        } else {
+            proxy_wasm::hostcalls::log(LogLevel::Info, "No concurrency configuration provided, no limits applied").ok();
+            true
+        }
+    }
+
+    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(ConcurrencyFilter {
+            config: self.config.clone(),
+            counter_key: None,
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+struct ConcurrencyFilter {
+    config: FilterConfig,
+    /// Shared-data key of the in-flight counter this request incremented, if any.
+    counter_key: Option<String>,
+}
+
+impl Context for ConcurrencyFilter {}
+
+impl HttpContext for ConcurrencyFilter {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        enter_hook("on_http_request_headers");
+        check_filter_position("concurrency", self.config.expected_position);
+
+        let path = self.get_http_request_header(":path").unwrap_or_default();
+        let (key, max_concurrent) = match self.limit_for_path(&path) {
+            Some(limit) => limit,
+            None => return Action::Continue,
+        };
+
+        match self.adjust_in_flight(&key, Some(CAS_RETRIES), |count| acquire_slot(count, max_concurrent)) {
+            InFlightUpdate::Updated(_) => {
+                self.counter_key = Some(key);
+                Action::Continue
+            }
+            InFlightUpdate::Refused => {
+                proxy_wasm::hostcalls::log(
+                    LogLevel::Warn,
+                    &format!("Shedding request for {}: in-flight limit of {} reached", path, max_concurrent),
+                ).ok();
+                self.shed("concurrency_limit")
+            }
+            InFlightUpdate::Contended => {
+                // The counter is changing too fast to take a slot, which only happens when many
+                // requests arrive at once, so shed this one rather than run it uncounted
+                proxy_wasm::hostcalls::log(
+                    LogLevel::Warn,
+                    &format!("Shedding request for {}: in-flight counter {} still contended after {} attempts", path, key, CAS_RETRIES),
+                ).ok();
+                self.shed("concurrency_contention")
+            }
+            InFlightUpdate::Failed(status) => {
+                // Shared data is unusable, so fail open instead of shedding every request
+                proxy_wasm::hostcalls::log(
+                    LogLevel::Error,
+                    &format!("Failed to update in-flight counter {}: {:?}; not limiting this request", key, status),
+                ).ok();
+                Action::Continue
+            }
+        }
+    }
+
+    fn on_log(&mut self) {
+        enter_hook("on_log");
+        // Runs for every stream that reached this filter, including ones a later filter
+        // rejected or the client reset, so the slot taken above is always released. Losing the
+        // race to another worker is retried until the release lands, since giving up would leak
+        // the slot for good
+        if let Some(key) = self.counter_key.take() {
+            if let InFlightUpdate::Failed(status) = self.adjust_in_flight(&key, None, release_slot) {
+                proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Failed to release in-flight slot for {}: {:?}", key, status)).ok();
+            }
+        }
+    }
+}
+
+impl ConcurrencyFilter {
+    /// The counter key and limit of the longest matching route, else the global limit.
+    fn limit_for_path(&self, path: &str) -> Option<(String, u64)> {
+        let route = self.config.routes.iter()
+            .filter(|route| path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.len());
+        match route {
+            Some(route) => Some((format!("marchproxy_inflight_route_{}", route.prefix), route.max_concurrent)),
+            None => self.config.max_concurrent.map(|max| (String::from("marchproxy_inflight_global"), max)),
+        }
+    }
+
+    /// Applies `update` to the shared in-flight counter with compare-and-swap so every worker
+    /// sees one count, making at most `attempts` attempts (unlimited when None).
+    fn adjust_in_flight(&self, key: &str, attempts: Option<usize>, update: impl Fn(u64) -> Option<u64>) -> InFlightUpdate {
+        compare_and_swap(
+            attempts,
+            || {
+                let (data, cas) = self.get_shared_data(key);
+                let count = data
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(u64::from_le_bytes)
+                    .unwrap_or(0);
+                (count, cas)
+            },
+            |next, cas| self.set_shared_data(key, Some(&next.to_le_bytes()), cas),
+            update,
+        )
+    }
+
+    /// Rejects the request with 503 and `retry-after`, recording `reason`.
+    fn shed(&self, reason: &str) -> Action {
+        let retry_after = self.config.retry_after_seconds.to_string();
+        self.reject(
+            503,
+            reason,
+            vec![
+                ("content-type", "application/json"),
+                ("retry-after", retry_after.as_str()),
+            ],
+            b"{\"error\":\"Too many concurrent requests\"}",
+        )
+    }
+
+    /// Sends the rejection response and records which filter rejected the request and why,
+    /// both as `marchproxy.rejected_by`/`marchproxy.rejection_reason` filter state and as metrics.
+    fn reject(&self, status: u32, reason: &str, headers: Vec<(&str, &str)>, body: &[u8]) -> Action {
+        record_rejection("concurrency", reason);
+        self.send_http_response(status, headers, Some(body));
+        Action::Pause
+    }
+}
+
+/// The count after taking a slot, or None when `max_concurrent` are already in flight.
+fn acquire_slot(count: u64, max_concurrent: u64) -> Option<u64> {
+    (count < max_concurrent).then_some(count + 1)
+}
+
+/// The count after giving a slot back, never going below zero.
+fn release_slot(count: u64) -> Option<u64> {
+    Some(count.saturating_sub(1))
+}
+
+/// Compare-and-swap loop behind `adjust_in_flight`. `load` reads the count and its CAS token and
+/// `store` writes a new count against that token; only a CAS mismatch is retried.
+fn compare_and_swap(
+    attempts: Option<usize>,
+    mut load: impl FnMut() -> (u64, Option<u32>),
+    mut store: impl FnMut(u64, Option<u32>) -> Result<(), Status>,
+    update: impl Fn(u64) -> Option<u64>,
+) -> InFlightUpdate {
+    let mut attempt = 0;
+    while attempts.is_none_or(|max| attempt < max) {
+        attempt += 1;
+        let (count, cas) = load();
+        let next = match update(count) {
+            Some(next) => next,
+            None => return InFlightUpdate::Refused,
+        };
+        match store(next, cas) {
+            Ok(()) => return InFlightUpdate::Updated(next),
+            Err(Status::CasMismatch) => continue,
+            Err(status) => return InFlightUpdate::Failed(status),
+        }
+    }
+    InFlightUpdate::Contended
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn acquire_slot_stops_at_limit() {
+        assert_eq!(acquire_slot(0, 2), Some(1));
+        assert_eq!(acquire_slot(1, 2), Some(2));
+        assert_eq!(acquire_slot(2, 2), None);
+        assert_eq!(acquire_slot(5, 2), None);
+        assert_eq!(acquire_slot(0, 0), None);
+    }
+
+    #[test]
+    fn release_slot_saturates_at_zero() {
+        assert_eq!(release_slot(3), Some(2));
+        assert_eq!(release_slot(0), Some(0));
+    }
+
+    #[test]
+    fn compare_and_swap_refuses_at_limit() {
+        let outcome = compare_and_swap(Some(CAS_RETRIES), || (2, Some(1)), |_, _| Ok(()), |count| acquire_slot(count, 2));
+        assert_eq!(outcome, InFlightUpdate::Refused);
+    }
+
+    #[test]
+    fn compare_and_swap_retries_cas_mismatch() {
+        let count = Cell::new(0u64);
+        let losses = Cell::new(2);
+        let outcome = compare_and_swap(
+            Some(CAS_RETRIES),
+            || (count.get(), Some(7)),
+            |next, _| {
+                if losses.get() > 0 {
+                    // Another worker took a slot first
+                    losses.set(losses.get() - 1);
+                    count.set(count.get() + 1);
+                    return Err(Status::CasMismatch);
+                }
+                count.set(next);
+                Ok(())
+            },
+            |count| acquire_slot(count, 10),
+        );
+        assert_eq!(outcome, InFlightUpdate::Updated(3));
+        assert_eq!(count.get(), 3);
+    }
+
+    #[test]
+    fn compare_and_swap_reports_contention_apart_from_limit() {
+        let attempts = Cell::new(0);
+        let outcome = compare_and_swap(
+            Some(CAS_RETRIES),
+            || (0, Some(1)),
+            |_, _| {
+                attempts.set(attempts.get() + 1);
+                Err(Status::CasMismatch)
+            },
+            |count| acquire_slot(count, 10),
+        );
+        assert_eq!(outcome, InFlightUpdate::Contended);
+        assert_eq!(attempts.get(), CAS_RETRIES);
+    }
+
+    #[test]
+    fn unbounded_release_retries_until_it_lands() {
+        let attempts = Cell::new(0);
+        let outcome = compare_and_swap(
+            None,
+            || (4, Some(1)),
+            |_, _| {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 50 { Err(Status::CasMismatch) } else { Ok(()) }
+            },
+            release_slot,
+        );
+        assert_eq!(outcome, InFlightUpdate::Updated(3));
+        assert_eq!(attempts.get(), 50);
+    }
+
+    #[test]
+    fn compare_and_swap_gives_up_on_other_errors() {
+        let outcome = compare_and_swap(None, || (1, None), |_, _| Err(Status::InternalFailure), release_slot);
+        assert_eq!(outcome, InFlightUpdate::Failed(Status::InternalFailure));
+    }
+}
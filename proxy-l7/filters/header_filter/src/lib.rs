@@ -0,0 +1,630 @@
+// MarchProxy Header Filter (WASM)
+// Request/response header transformations applied at the edge
+
+use marchproxy_filter_common::body::{default_max_buffer_bytes, BodyBuffer};
+use marchproxy_filter_common::panic::{enter_hook, install_panic_hook};
+use marchproxy_filter_common::{check_filter_position, parse_config};
+use proxy_wasm::traits::*;
+use proxy_wasm::types::*;
+use base64::Engine;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    install_panic_hook("header");
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(HeaderFilterRoot {
+            config: FilterConfig::default(),
+        })
+    });
+}}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+struct FilterConfig {
+    strip_response_headers: Vec<String>,
+    server_header: Option<String>,
+    set_forwarded_headers: bool,
+    trust_incoming_forwarded: bool,
+    default_deadline_budget_ms: Option<u64>,
+    deadline_budgets: Vec<DeadlineBudget>,
+    path_rewrites: Vec<PathRewrite>,
+    static_routes: Vec<StaticRoute>,
+    expected_position: Option<usize>,
+    error_body_overrides: HashMap<u32, String>,
+    error_body_content_type: String,
+    /// Upstream headers copied onto a replaced error response; all others are dropped.
+    error_body_preserved_headers: Vec<String>,
+    /// Most bytes of an upstream error body held to check whether it is blank.
+    max_buffer_bytes: usize,
+}
+
+/// Total time budget for requests under `prefix`, propagated upstream as a deadline.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct DeadlineBudget {
+    prefix: String,
+    budget_ms: u64,
+}
+
+/// Rewrites the request path when `pattern` matches; `replacement` may reference capture
+/// groups as `$1` or `${name}`. Patterns are anchored at both ends, so they must match the
+/// whole path and the replacement becomes the whole new path. Patterns containing a literal
+/// `?` (`\?`) are matched against the full `:path` so they can rewrite the query string; all
+/// others see only the path and the original query string is carried over unchanged.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct PathRewrite {
+    pattern: String,
+    replacement: String,
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+impl PathRewrite {
+    /// Compiles `pattern`, anchored so an unanchored pattern cannot rewrite just part of a path.
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        self.regex = Some(Regex::new(&format!("^(?:{})$", self.pattern))?);
+        Ok(())
+    }
+
+    fn includes_query(&self) -> bool {
+        self.pattern.contains("\\?")
+    }
+
+    fn apply(&self, full_path: &str) -> Option<String> {
+        let regex = self.regex.as_ref()?;
+        if self.includes_query() {
+            return regex.is_match(full_path)
+                .then(|| regex.replace(full_path, self.replacement.as_str()).into_owned());
+        }
+
+        let (path, query) = match full_path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (full_path, None),
+        };
+        if !regex.is_match(path) {
+            return None;
+        }
+        let rewritten = regex.replace(path, self.replacement.as_str());
+        Some(match query {
+            Some(query) => format!("{}?{}", rewritten, query),
+            None => rewritten.into_owned(),
+        })
+    }
+}
+
+/// Canned response served for requests under `prefix` without contacting the upstream.
+/// An inline `body` may contain `{host}`, replaced with the request's `:authority` when that is a
+/// well-formed host (see `static_body_host`); `body_base64` is sent verbatim and takes precedence
+/// when both are set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct StaticRoute {
+    prefix: String,
+    #[serde(default = "default_static_status")]
+    status: u32,
+    #[serde(default = "default_static_content_type")]
+    content_type: String,
+    #[serde(default)]
+    body: String,
+    #[serde(default)]
+    body_base64: Option<String>,
+    #[serde(skip)]
+    decoded_body: Option<Vec<u8>>,
+}
+
+fn default_static_status() -> u32 {
+    200
+}
+
+fn default_static_content_type() -> String {
+    String::from("text/plain; charset=utf-8")
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            strip_response_headers: vec![
+                String::from("server"),
+                String::from("x-powered-by"),
+            ],
+            server_header: None,
+            set_forwarded_headers: false,
+            trust_incoming_forwarded: false,
+            default_deadline_budget_ms: None,
+            deadline_budgets: Vec::new(),
+            path_rewrites: Vec::new(),
+            static_routes: Vec::new(),
+            expected_position: None,
+            error_body_overrides: HashMap::new(),
+            error_body_content_type: String::from("application/json"),
+            error_body_preserved_headers: [
+                "retry-after", "www-authenticate", "set-cookie", "cache-control", "expires", "pragma", "vary",
+            ].into_iter().map(String::from).collect(),
+            max_buffer_bytes: default_max_buffer_bytes(),
+        }
+    }
+}
+
+struct HeaderFilterRoot {
+    config: FilterConfig,
+}
+
+impl Context for HeaderFilterRoot {}
+
+impl RootContext for HeaderFilterRoot {
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        enter_hook("on_configure");
+        if let Some(config_bytes) = self.get_plugin_configuration() {
+            match parse_config::<FilterConfig>(&config_bytes) {
+                Ok(mut config) => {
+                    // Header names are matched case-insensitively
+                    for header in &mut config.strip_response_headers {
+                        *header = header.to_lowercase();
+                    }
+                    for rule in &mut config.path_rewrites {
+                        if let Err(e) = rule.compile() {
+                            proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Invalid path rewrite pattern '{}': {}", rule.pattern, e)).ok();
+                            return false;
+                        }
+                    }
+                    for route in &mut config.static_routes {
+                        if let Some(encoded) = &route.body_base64 {
+                            match base64::engine::general_purpose::STANDARD.decode(encoded) {
+                                Ok(body) => route.decoded_body = Some(body),
+                                Err(e) => {
+                                    proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Invalid base64 body for static route '{}': {}", route.prefix, e)).ok();
+                                    return false;
+                                }
+                            }
+                        }
+                    }
+                    self.config = config;
+                    proxy_wasm::hostcalls::log(LogLevel::Info, "Header filter configured successfully").ok();
+                    true
+                }
+                Err(e) => {
+                    proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Failed to parse header configuration: {}", e)).ok();
+                    false
+                }
+            }
+        } else {
+            proxy_wasm::hostcalls::log(LogLevel::Info, "No header configuration provided, using defaults").ok();
+            true
+        }
+    }
+
+    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(HeaderFilter {
+            config: self.config.clone(),
+            pending_error_body: None,
+            held_error_body: BodyBuffer::new(self.config.max_buffer_bytes),
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+struct HeaderFilter {
+    config: FilterConfig,
+    /// Status of an overridable error response whose body may be blank, held until the body
+    /// shows whether it is.
+    pending_error_body: Option<u32>,
+    held_error_body: BodyBuffer,
+}
+
+impl Context for HeaderFilter {}
+
+impl HttpContext for HeaderFilter {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        enter_hook("on_http_request_headers");
+        check_filter_position("header", self.config.expected_position);
+
+        let path = self.get_http_request_header(":path").unwrap_or_default();
+        if let Some(route) = self.static_route_for_path(&path) {
+            let body = match &route.decoded_body {
+                Some(body) => body.clone(),
+                None => {
+                    let authority = self.get_http_request_header(":authority").unwrap_or_default();
+                    route.body.replace("{host}", static_body_host(&authority)).into_bytes()
+                }
+            };
+            self.send_http_response(
+                route.status,
+                vec![("content-type", route.content_type.as_str())],
+                Some(&body),
+            );
+            return Action::Pause;
+        }
+
+        if self.config.set_forwarded_headers {
+            self.set_forwarded_headers();
+        }
+
+        if let Some(budget_ms) = self.deadline_budget_for_path(&path) {
+            let remaining_ms = budget_ms.saturating_sub(self.elapsed_ms());
+            self.set_http_request_header("x-request-deadline-ms", Some(&remaining_ms.to_string()));
+        }
+
+        // Budgets are keyed on the public path, so rewriting happens last
+        if let Some(rewritten) = self.config.path_rewrites.iter().find_map(|rule| rule.apply(&path)) {
+            self.set_http_request_header(":path", Some(&rewritten));
+        }
+
+        Action::Continue
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        enter_hook("on_http_response_headers");
+        if let Some(status) = self.overridden_error_status() {
+            let content_length = self.get_http_response_header("content-length");
+            match response_body_state(end_of_stream, content_length.as_deref(), self.config.max_buffer_bytes) {
+                BodyState::Empty => return self.send_error_body(status),
+                BodyState::Present => {}
+                BodyState::Unknown => self.pending_error_body = Some(status),
+            }
+        }
+
+        // Remove headers that fingerprint the backend
+        for (name, _) in self.get_http_response_headers() {
+            if self.config.strip_response_headers.contains(&name.to_lowercase()) {
+                self.set_http_response_header(&name, None);
+            }
+        }
+
+        if let Some(server) = &self.config.server_header {
+            self.set_http_response_header("server", Some(server));
+        }
+
+        // Hold the headers until the body shows whether it is blank
+        if self.pending_error_body.is_some() {
+            return Action::Pause;
+        }
+        Action::Continue
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        enter_hook("on_http_response_body");
+        let status = match self.pending_error_body {
+            Some(status) => status,
+            None => return Action::Continue,
+        };
+
+        // Pausing makes Envoy buffer the body, so each call sees all of it; copy only what is new
+        let mut held = std::mem::take(&mut self.held_error_body);
+        let offset = held.data().len();
+        held.append(body_size.saturating_sub(offset), |len| self.get_http_response_body(offset, len));
+        if held.truncated() || !is_blank(held.data()) {
+            // The upstream sent a body of its own; resuming also releases the held headers
+            self.pending_error_body = None;
+            return Action::Continue;
+        }
+        if end_of_stream {
+            self.pending_error_body = None;
+            return self.send_error_body(status);
+        }
+        self.held_error_body = held;
+        Action::Pause
+    }
+}
+
+impl HeaderFilter {
+    /// Synthesizes `X-Forwarded-For/-Proto/-Host`. When incoming values are trusted the client IP
+    /// is appended to the existing chain and existing proto/host are kept; otherwise all three
+    /// are replaced with what this hop observed.
+    fn set_forwarded_headers(&self) {
+        let client_ip = self.source_address().map(|address| address.ip().to_string());
+
+        let trusted = self.config.trust_incoming_forwarded;
+
+        if let Some(client_ip) = client_ip {
+            let incoming = self.get_http_request_header("x-forwarded-for");
+            let forwarded_for = merge_forwarded_for(incoming.as_deref(), &client_ip, trusted);
+            self.set_http_request_header("x-forwarded-for", Some(&forwarded_for));
+        }
+
+        if !trusted || self.get_http_request_header("x-forwarded-proto").is_none() {
+            // Envoy reports an empty TLS version for plaintext connections
+            let is_tls = self.get_property(vec!["connection", "tls_version"])
+                .map(|version| !version.is_empty())
+                .unwrap_or(false);
+            self.set_http_request_header("x-forwarded-proto", Some(if is_tls { "https" } else { "http" }));
+        }
+
+        if !trusted || self.get_http_request_header("x-forwarded-host").is_none() {
+            if let Some(host) = self.get_http_request_header(":authority") {
+                self.set_http_request_header("x-forwarded-host", Some(&host));
+            }
+        }
+    }
+
+    /// The downstream connection's address: the peer that actually connected to Envoy, which
+    /// unlike X-Forwarded-For cannot be set by the client.
+    fn source_address(&self) -> Option<SocketAddr> {
+        self.get_property(vec!["source", "address"])
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .and_then(|address| parse_source_address(&address))
+    }
+
+    /// The upstream response status when `error_body_overrides` has a body for it.
+    fn overridden_error_status(&self) -> Option<u32> {
+        if self.config.error_body_overrides.is_empty() {
+            return None;
+        }
+        let status: u32 = self.get_http_response_header(":status")?.parse().ok()?;
+        self.config.error_body_overrides.contains_key(&status).then_some(status)
+    }
+
+    /// Replaces the empty upstream response with the configured body for its status. The
+    /// response is re-sent locally, so Envoy sets `content-length` to match, and only the
+    /// upstream headers listed in `error_body_preserved_headers` are kept.
+    fn send_error_body(&self, status: u32) -> Action {
+        let body = match self.config.error_body_overrides.get(&status) {
+            Some(body) => body,
+            None => return Action::Continue,
+        };
+        let upstream_headers = self.get_http_response_headers();
+        let mut headers = vec![("content-type", self.config.error_body_content_type.as_str())];
+        headers.extend(preserved_headers(&upstream_headers, &self.config.error_body_preserved_headers));
+        if let Some(server) = &self.config.server_header {
+            headers.push(("server", server.as_str()));
+        }
+        self.send_http_response(status, headers, Some(body.as_bytes()));
+        Action::Pause
+    }
+
+    fn static_route_for_path(&self, path: &str) -> Option<&StaticRoute> {
+        self.config.static_routes.iter()
+            .filter(|route| path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.len())
+    }
+
+    fn deadline_budget_for_path(&self, path: &str) -> Option<u64> {
+        self.config.deadline_budgets.iter()
+            .filter(|budget| path.starts_with(&budget.prefix))
+            .max_by_key(|budget| budget.prefix.len())
+            .map(|budget| budget.budget_ms)
+            .or(self.config.default_deadline_budget_ms)
+    }
+
+    /// Milliseconds since Envoy received the request, read from the `request.time` attribute
+    /// (nanoseconds since the epoch). Zero when the attribute is unavailable.
+    fn elapsed_ms(&self) -> u64 {
+        let request_start_ns = match self.get_property(vec!["request", "time"]) {
+            Some(bytes) if bytes.len() == 8 => {
+                let mut raw = [0u8; 8];
+                raw.copy_from_slice(&bytes);
+                u64::from_le_bytes(raw)
+            }
+            _ => return 0,
+        };
+        let now_ns = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default().as_nanos() as u64;
+        now_ns.saturating_sub(request_start_ns) / 1_000_000
+    }
+}
+
+/// The X-Forwarded-For sent upstream: `client_ip` appended to the incoming chain when that is
+/// trusted, else `client_ip` alone so a client cannot forge the hops before it.
+fn merge_forwarded_for(incoming: Option<&str>, client_ip: &str, trusted: bool) -> String {
+    match incoming.map(str::trim) {
+        Some(existing) if trusted && !existing.is_empty() => format!("{}, {}", existing, client_ip),
+        _ => client_ip.to_string(),
+    }
+}
+
+/// Whether an upstream response has a body, as far as its headers tell.
+#[derive(Debug, PartialEq)]
+enum BodyState {
+    Empty,
+    Present,
+    /// No `content-length` (e.g. chunked), or one of at most `max_buffer_bytes` that may be
+    /// blank: only the body callbacks can tell.
+    Unknown,
+}
+
+fn response_body_state(end_of_stream: bool, content_length: Option<&str>, max_buffer_bytes: usize) -> BodyState {
+    if end_of_stream {
+        return BodyState::Empty;
+    }
+    match content_length.map(|length| length.trim().parse::<usize>()) {
+        Some(Ok(0)) => BodyState::Empty,
+        Some(Ok(length)) if length <= max_buffer_bytes => BodyState::Unknown,
+        Some(_) => BodyState::Present,
+        None => BodyState::Unknown,
+    }
+}
+
+/// True for a body with nothing but whitespace, such as the lone newline some servers send
+/// with an error status. Such a body is replaced like an empty one.
+fn is_blank(body: &[u8]) -> bool {
+    body.iter().all(u8::is_ascii_whitespace)
+}
+
+/// The upstream headers named in `allowlist` (case-insensitively), every value of repeated
+/// ones such as `set-cookie` included.
+fn preserved_headers<'a>(headers: &'a [(String, String)], allowlist: &[String]) -> Vec<(&'a str, &'a str)> {
+    headers.iter()
+        .filter(|(name, _)| allowlist.iter().any(|allowed| allowed.eq_ignore_ascii_case(name)))
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+/// The client-supplied `:authority` as substituted for `{host}` in a static body, or an empty
+/// string unless it is a DNS name or IP with an optional port. Static bodies may be HTML, so
+/// anything else (markup, quotes, whitespace) must never be reflected into them.
+fn static_body_host(authority: &str) -> &str {
+    if is_valid_authority(authority) {
+        authority
+    } else {
+        ""
+    }
+}
+
+/// Whether `authority` is `host[:port]` with a host of letters, digits, `-` and `.`, or
+/// `[ipv6][:port]`.
+fn is_valid_authority(authority: &str) -> bool {
+    let (host_valid, port) = match authority.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((ip, port)) => (ip.parse::<Ipv6Addr>().is_ok(), port),
+            None => return false,
+        },
+        None => {
+            let (host, port) = authority.split_at(authority.find(':').unwrap_or(authority.len()));
+            let host_valid = !host.is_empty()
+                && host.len() <= 253
+                && host.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+            (host_valid, port)
+        }
+    };
+    let port_valid = port.is_empty()
+        || port.strip_prefix(':').is_some_and(|digits| {
+            !digits.is_empty() && digits.len() <= 5 && digits.bytes().all(|b| b.is_ascii_digit())
+        });
+    host_valid && port_valid
+}
+
+/// Parses Envoy's `source.address` (`ip:port`, `[ipv6]:port`, or a bare IP reported with
+/// port 0 for non-IP transports).
+fn parse_source_address(address: &str) -> Option<SocketAddr> {
+    address.parse::<SocketAddr>().ok()
+        .or_else(|| address.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(pattern: &str, replacement: &str) -> PathRewrite {
+        let mut rule = PathRewrite { pattern: String::from(pattern), replacement: String::from(replacement), regex: None };
+        rule.compile().unwrap();
+        rule
+    }
+
+    #[test]
+    fn rewrite_replaces_whole_path() {
+        let rule = rewrite("^/v1/(.*)$", "/internal/$1");
+        assert_eq!(rule.apply("/v1/users/7").as_deref(), Some("/internal/users/7"));
+        assert_eq!(rule.apply("/v2/users/7"), None);
+
+        let rule = rewrite("/legacy/(?P<id>[0-9]+)", "/items/${id}");
+        assert_eq!(rule.apply("/legacy/42").as_deref(), Some("/items/42"));
+    }
+
+    #[test]
+    fn rewrite_patterns_are_anchored() {
+        // Unanchored, this would rewrite the middle of the path and leave the rest in place
+        let rule = rewrite("/v1/", "/v2/");
+        assert_eq!(rule.apply("/api/v1/users"), None);
+        assert_eq!(rule.apply("/v1/users"), None);
+        assert_eq!(rule.apply("/v1/").as_deref(), Some("/v2/"));
+
+        let rule = rewrite("/v1/(.*)", "/v2/$1");
+        assert_eq!(rule.apply("/v1/a/v1/b").as_deref(), Some("/v2/a/v1/b"));
+    }
+
+    #[test]
+    fn rewrite_keeps_query_string() {
+        let rule = rewrite("^/v1/(.*)$", "/internal/$1");
+        assert_eq!(rule.apply("/v1/search?q=a&page=2").as_deref(), Some("/internal/search?q=a&page=2"));
+        assert_eq!(rule.apply("/v1/search?next=/v1/x").as_deref(), Some("/internal/search?next=/v1/x"));
+    }
+
+    #[test]
+    fn rewrite_with_query_pattern_sees_query() {
+        let rule = rewrite(r"/search\?q=(.*)", "/find?term=$1");
+        assert!(rule.includes_query());
+        assert_eq!(rule.apply("/search?q=rust").as_deref(), Some("/find?term=rust"));
+        assert_eq!(rule.apply("/search"), None);
+    }
+
+    #[test]
+    fn forwarded_for_single_hop() {
+        assert_eq!(merge_forwarded_for(None, "203.0.113.7", true), "203.0.113.7");
+        assert_eq!(merge_forwarded_for(Some(""), "203.0.113.7", true), "203.0.113.7");
+        assert_eq!(merge_forwarded_for(None, "2001:db8::7", false), "2001:db8::7");
+    }
+
+    #[test]
+    fn forwarded_for_appends_to_trusted_chain() {
+        assert_eq!(merge_forwarded_for(Some("198.51.100.1"), "10.0.0.2", true), "198.51.100.1, 10.0.0.2");
+        assert_eq!(
+            merge_forwarded_for(Some("198.51.100.1, 10.0.0.1 "), "10.0.0.2", true),
+            "198.51.100.1, 10.0.0.1, 10.0.0.2",
+        );
+    }
+
+    #[test]
+    fn forwarded_for_replaces_untrusted_chain() {
+        assert_eq!(merge_forwarded_for(Some("127.0.0.1"), "203.0.113.7", false), "203.0.113.7");
+        assert_eq!(merge_forwarded_for(Some("1.2.3.4, 10.0.0.1"), "203.0.113.7", false), "203.0.113.7");
+    }
+
+    #[test]
+    fn body_state_from_headers() {
+        assert_eq!(response_body_state(true, None, 4096), BodyState::Empty);
+        assert_eq!(response_body_state(true, Some("12"), 4096), BodyState::Empty);
+        assert_eq!(response_body_state(false, Some("0"), 4096), BodyState::Empty);
+        assert_eq!(response_body_state(false, Some("12"), 4096), BodyState::Unknown);
+        assert_eq!(response_body_state(false, Some("4097"), 4096), BodyState::Present);
+        assert_eq!(response_body_state(false, Some("junk"), 4096), BodyState::Present);
+        assert_eq!(response_body_state(false, None, 4096), BodyState::Unknown);
+    }
+
+    #[test]
+    fn blank_bodies_are_replaceable() {
+        assert!(is_blank(b""));
+        assert!(is_blank(b"\r\n"));
+        assert!(is_blank(b"  \t\n"));
+        assert!(!is_blank(b"{}"));
+        assert!(!is_blank(b"\nBad Gateway\n"));
+    }
+
+    #[test]
+    fn preserved_headers_follow_allowlist() {
+        let upstream = vec![
+            (String::from(":status"), String::from("503")),
+            (String::from("Retry-After"), String::from("30")),
+            (String::from("set-cookie"), String::from("a=1")),
+            (String::from("set-cookie"), String::from("b=2")),
+            (String::from("content-length"), String::from("0")),
+            (String::from("x-backend-debug"), String::from("node-7")),
+        ];
+        let allowlist = FilterConfig::default().error_body_preserved_headers;
+        assert_eq!(
+            preserved_headers(&upstream, &allowlist),
+            vec![("Retry-After", "30"), ("set-cookie", "a=1"), ("set-cookie", "b=2")],
+        );
+        assert!(preserved_headers(&upstream, &[]).is_empty());
+    }
+
+    #[test]
+    fn static_body_host_keeps_well_formed_authorities() {
+        assert_eq!(static_body_host("api.example.com"), "api.example.com");
+        assert_eq!(static_body_host("api.example.com:8443"), "api.example.com:8443");
+        assert_eq!(static_body_host("10.0.0.1:80"), "10.0.0.1:80");
+        assert_eq!(static_body_host("[2001:db8::1]:8080"), "[2001:db8::1]:8080");
+        assert_eq!(static_body_host("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn static_body_host_drops_markup() {
+        assert_eq!(static_body_host("<script>alert(1)</script>"), "");
+        assert_eq!(static_body_host("example.com\"><img src=x onerror=alert(1)>"), "");
+        assert_eq!(static_body_host("example.com:80<b>"), "");
+        assert_eq!(static_body_host("[::1<script>]"), "");
+        assert_eq!(static_body_host("example.com/path"), "");
+        assert_eq!(static_body_host(""), "");
+    }
+
+    #[test]
+    fn static_body_host_rejects_malformed_ports() {
+        assert_eq!(static_body_host("example.com:"), "");
+        assert_eq!(static_body_host("example.com:80:80"), "");
+        assert_eq!(static_body_host("example.com:123456"), "");
+        assert_eq!(static_body_host("[::1]80"), "");
+    }
+}
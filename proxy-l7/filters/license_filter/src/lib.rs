@@ -1,16 +1,23 @@
 // MarchProxy License Filter (WASM)
 // Enterprise feature gating based on license validation
 
+use marchproxy_filter_common::panic::{enter_hook, install_panic_hook};
+use marchproxy_filter_common::secrets::{redact_secrets, resolve_secret, secret_eq, secret_fingerprint};
+use marchproxy_filter_common::{authority_host, check_filter_position, increment_metric, parse_config, record_rejection};
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::collections::HashMap;
 
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Info);
+    install_panic_hook("license");
     proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
         Box::new(LicenseFilterRoot {
             config: FilterConfig::default(),
+            downgraded: false,
         })
     });
 }}
@@ -18,50 +25,247 @@ proxy_wasm::main! {{
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct FilterConfig {
     license_key: String,
+    #[serde(default)]
     is_enterprise: bool,
+    #[serde(default = "default_features")]
     features: HashMap<String, bool>,
+    #[serde(default = "default_max_proxies")]
     max_proxies: u32,
+    #[serde(default)]
     current_proxies: u32,
+    #[serde(default)]
+    limit_action: LimitAction,
+    #[serde(default)]
+    license_expires_at: Option<u64>,
+    #[serde(default)]
+    maintenance_mode: bool,
+    #[serde(default)]
+    override_header: Option<String>,
+    #[serde(default)]
+    override_secret: String,
+    #[serde(default)]
+    feature_exemptions: Vec<String>,
+    #[serde(default)]
+    strict_features: bool,
+    #[serde(default)]
+    client_quotas: HashMap<String, u64>,
+    #[serde(default)]
+    anonymous_quota: Option<u64>,
+    #[serde(default)]
+    features_by_sni: HashMap<String, HashMap<String, bool>>,
+    #[serde(default)]
+    expected_position: Option<usize>,
+    #[serde(default)]
+    feature_dependencies: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    enable_config_dump: bool,
+    #[serde(default = "default_config_dump_path")]
+    config_dump_path: String,
+    #[serde(default)]
+    config_dump_secret: String,
+}
+
+/// Path prefixes that require an enterprise feature, and the feature each one requires.
+const FEATURE_PATHS: [(&str, &str); 5] = [
+    ("/api/v1/traffic-shaping", "advanced_routing"),
+    ("/api/v1/multi-cloud", "multi_cloud"),
+    ("/api/v1/tracing", "distributed_tracing"),
+    ("/api/v1/zero-trust", "zero_trust"),
+    ("/api/v1/advanced-rate-limit", "rate_limiting"),
+];
+
+/// What to do when the proxy count exceeds the licensed maximum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+enum LimitAction {
+    #[default]
+    Block,
+    WarnOnly,
+}
+
+/// Machine-readable reason sent as `error_code` in every license rejection body and as the
+/// `x-license-error-code` header, so client SDKs can branch without parsing messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LicenseErrorCode {
+    FeatureGated,
+    LicenseDowngraded,
+    LicenseExpired,
+    ProxyLimit,
+    QuotaExceeded,
+    Maintenance,
+}
+
+impl LicenseErrorCode {
+    fn as_str(self) -> &'static str {
+        match self {
+            LicenseErrorCode::FeatureGated => "FEATURE_GATED",
+            LicenseErrorCode::LicenseDowngraded => "LICENSE_DOWNGRADED",
+            LicenseErrorCode::LicenseExpired => "LICENSE_EXPIRED",
+            LicenseErrorCode::ProxyLimit => "PROXY_LIMIT",
+            LicenseErrorCode::QuotaExceeded => "QUOTA_EXCEEDED",
+            LicenseErrorCode::Maintenance => "MAINTENANCE",
+        }
+    }
+}
+
+/// Fields a partial license config may omit; each falls back to its Community value.
+const DEFAULTED_FIELDS: [&str; 4] = ["is_enterprise", "features", "max_proxies", "current_proxies"];
+
+impl FilterConfig {
+    /// True once `license_expires_at` (Unix seconds) has passed at `now`.
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.license_expires_at, Some(expires_at) if now >= expires_at)
+    }
+
+    /// The monthly quota for a verified client id from `client_quotas`, or `anonymous_quota`
+    /// for requests that carry none.
+    fn quota_for(&self, client_id: Option<&str>) -> Option<u64> {
+        match client_id {
+            Some(client_id) => self.client_quotas.get(client_id).copied(),
+            None => self.anonymous_quota,
+        }
+    }
+
+    /// The edition as the `x-license-edition` value the metrics filter keys its sample rate on.
+    fn edition(&self) -> &'static str {
+        if self.is_enterprise { "enterprise" } else { "community" }
+    }
+}
+
+/// The Community feature set: basic proxying only.
+fn default_features() -> HashMap<String, bool> {
+    let mut features = HashMap::new();
+    features.insert("basic_proxy".to_string(), true);
+    features.insert("rate_limiting".to_string(), false);
+    features.insert("advanced_routing".to_string(), false);
+    features.insert("multi_cloud".to_string(), false);
+    features.insert("distributed_tracing".to_string(), false);
+    features.insert("zero_trust".to_string(), false);
+    features
+}
+
+fn default_max_proxies() -> u32 {
+    3
+}
+
+fn default_config_dump_path() -> String {
+    String::from("/marchproxy/config/license")
 }
 
 impl Default for FilterConfig {
     fn default() -> Self {
-        let mut features = HashMap::new();
-        features.insert("basic_proxy".to_string(), true);
-        features.insert("rate_limiting".to_string(), false);
-        features.insert("advanced_routing".to_string(), false);
-        features.insert("multi_cloud".to_string(), false);
-        features.insert("distributed_tracing".to_string(), false);
-        features.insert("zero_trust".to_string(), false);
-
         Self {
             license_key: String::from("COMMUNITY"),
             is_enterprise: false,
-            features,
-            max_proxies: 3,
+            features: default_features(),
+            max_proxies: default_max_proxies(),
             current_proxies: 0,
+            limit_action: LimitAction::default(),
+            license_expires_at: None,
+            maintenance_mode: false,
+            override_header: None,
+            override_secret: String::new(),
+            feature_exemptions: Vec::new(),
+            strict_features: false,
+            client_quotas: HashMap::new(),
+            anonymous_quota: None,
+            features_by_sni: HashMap::new(),
+            expected_position: None,
+            feature_dependencies: HashMap::new(),
+            enable_config_dump: false,
+            config_dump_path: default_config_dump_path(),
+            config_dump_secret: String::new(),
         }
     }
 }
 
+/// Header the auth filter sets to the client id of a verified credential, after removing any
+/// copy the client sent. Quotas are keyed on it, so this filter must run after auth.
+const CLIENT_ID_HEADER: &str = "x-client-id";
+
+/// Quota bucket shared by requests without a verified client id.
+const ANONYMOUS_BUCKET: &str = "anonymous";
+
+/// Header that must carry `config_dump_secret` for a config dump.
+const CONFIG_DUMP_SECRET_HEADER: &str = "x-marchproxy-config-secret";
+
 struct LicenseFilterRoot {
     config: FilterConfig,
+    /// A config push moved the license from Enterprise to Community, and no later push has
+    /// restored it.
+    downgraded: bool,
 }
 
 impl Context for LicenseFilterRoot {}
 
 impl RootContext for LicenseFilterRoot {
     fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        enter_hook("on_configure");
         if let Some(config_bytes) = self.get_plugin_configuration() {
-            match serde_json::from_slice::<FilterConfig>(&config_bytes) {
-                Ok(config) => {
+            match parse_license_config(&config_bytes) {
+                Ok((mut config, defaulted)) => {
+                    // A partial config degrades to Community rather than leaving the filter unconfigured
+                    if !defaulted.is_empty() {
+                        proxy_wasm::hostcalls::log(
+                            LogLevel::Warn,
+                            &format!("License configuration is missing {}; using Community defaults for them", defaulted.join(", ")),
+                        ).ok();
+                    }
+                    match resolve_secret(&config.license_key) {
+                        Ok(license_key) => config.license_key = license_key,
+                        Err(e) => {
+                            proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Failed to resolve license key: {}", e)).ok();
+                            return false;
+                        }
+                    }
+                    match resolve_secret(&config.override_secret) {
+                        Ok(override_secret) => config.override_secret = override_secret,
+                        Err(e) => {
+                            proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Failed to resolve override secret: {}", e)).ok();
+                            return false;
+                        }
+                    }
+                    match resolve_secret(&config.config_dump_secret) {
+                        Ok(config_dump_secret) => config.config_dump_secret = config_dump_secret,
+                        Err(e) => {
+                            proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Failed to resolve config dump secret: {}", e)).ok();
+                            return false;
+                        }
+                    }
+                    if config.enable_config_dump && config.config_dump_secret.is_empty() {
+                        proxy_wasm::hostcalls::log(LogLevel::Error, "enable_config_dump requires a non-empty config_dump_secret").ok();
+                        return false;
+                    }
+                    if config.override_header.is_some() && config.override_secret.is_empty() {
+                        proxy_wasm::hostcalls::log(LogLevel::Error, "override_header requires a non-empty override_secret").ok();
+                        return false;
+                    }
+                    config.features_by_sni = std::mem::take(&mut config.features_by_sni)
+                        .into_iter()
+                        .map(|(host, features)| (host.to_lowercase(), features))
+                        .collect();
+
+                    match check_feature_maps(&config) {
+                        Ok(warnings) => {
+                            for warning in warnings {
+                                proxy_wasm::hostcalls::log(LogLevel::Warn, &warning).ok();
+                            }
+                        }
+                        Err(e) => {
+                            proxy_wasm::hostcalls::log(LogLevel::Error, &e).ok();
+                            return false;
+                        }
+                    }
+                    self.track_downgrade(&config);
                     self.config = config;
                     proxy_wasm::hostcalls::log(
                         LogLevel::Info,
                         &format!("License filter configured - Edition: {}",
                                 if self.config.is_enterprise { "Enterprise" } else { "Community" })
                     ).ok();
-                    proxy_wasm::hostcalls::log(LogLevel::Info, &format!("License: {}", self.config.license_key)).ok();
+                    proxy_wasm::hostcalls::log(
+                        LogLevel::Info,
+                        &format!("License key fingerprint: {}", secret_fingerprint(&self.config.license_key)),
+                    ).ok();
                     proxy_wasm::hostcalls::log(LogLevel::Info, &format!("Max proxies: {}", self.config.max_proxies)).ok();
                     true
                 }
@@ -79,6 +283,9 @@ impl RootContext for LicenseFilterRoot {
     fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
         Some(Box::new(LicenseFilter {
             config: self.config.clone(),
+            downgraded: self.downgraded,
+            over_limit: false,
+            override_feature: None,
         }))
     }
 
@@ -87,95 +294,743 @@ impl RootContext for LicenseFilterRoot {
     }
 }
 
+impl LicenseFilterRoot {
+    /// Flags a push that takes an Enterprise license down to Community. Requests already past
+    /// the filter keep the config they started with, while new ones are gated immediately and
+    /// told why with `LICENSE_DOWNGRADED`.
+    fn track_downgrade(&mut self, next: &FilterConfig) {
+        if self.config.is_enterprise && !next.is_enterprise {
+            proxy_wasm::hostcalls::log(LogLevel::Warn, "License downgraded from Enterprise to Community; revoking enterprise features").ok();
+            increment_metric("marchproxy_license_downgrade_total", 1);
+            self.downgraded = true;
+        } else if next.is_enterprise {
+            self.downgraded = false;
+        }
+    }
+}
+
 struct LicenseFilter {
     config: FilterConfig,
+    downgraded: bool,
+    over_limit: bool,
+    override_feature: Option<String>,
 }
 
 impl Context for LicenseFilter {}
 
 impl HttpContext for LicenseFilter {
     fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        enter_hook("on_http_request_headers");
+        // Envoy delivers the complete header map in this one callback; end_of_stream = false only
+        // means a body follows. Nothing decided here reads the body or trailers, so no need to wait
+        check_filter_position("license", self.config.expected_position);
+        if let Some(action) = self.serve_config_dump() {
+            return action;
+        }
+
+        if self.config.maintenance_mode {
+            proxy_wasm::hostcalls::log(LogLevel::Info, "Rejecting request: maintenance mode").ok();
+            return self.reject(
+                503,
+                LicenseErrorCode::Maintenance,
+                "maintenance",
+                Vec::new(),
+                serde_json::json!({"error": "Service is under maintenance"}),
+            );
+        }
+
+        self.select_host_features();
+
+        // An expired license serves Community until the control plane pushes a renewal
+        let now = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default().as_secs();
+        let expired = self.config.is_expired(now);
+        if expired {
+            self.config.is_enterprise = false;
+            self.config.features = default_features();
+        }
+
         // Get request path to determine which feature is being accessed
         let path = self.get_http_request_header(":path").unwrap_or_default();
 
+        // Validate any signed feature override, then drop it so upstream never sees it
+        if let Some(header) = self.config.override_header.clone() {
+            if let Some(token) = self.get_http_request_header(&header) {
+                self.override_feature = self.verify_override(&token);
+                self.set_http_request_header(&header, None);
+            }
+        }
+
         // Check for enterprise feature paths
         let required_feature = self.get_required_feature(&path);
 
         if let Some(feature) = required_feature {
-            if !self.is_feature_enabled(&feature) {
+            // Cohort split lets dashboards compare requests that got the feature with those that did not
+            let enabled = self.is_feature_enabled(&feature);
+            let cohort = if enabled { "enabled" } else { "disabled" };
+            increment_metric(&format!("marchproxy_feature_cohort_{}_{}", feature, cohort), 1);
+
+            // Exemptions only lift the feature gate; proxy-count limits below still apply
+            let exempt = self.config.feature_exemptions.iter().any(|prefix| path.starts_with(prefix.as_str()));
+            if !enabled && exempt {
+                proxy_wasm::hostcalls::log(
+                    LogLevel::Info,
+                    &format!("Feature '{}' gate bypassed by exemption for path {}", feature, path),
+                ).ok();
+                increment_metric("marchproxy_license_feature_exemptions_total", 1);
+            } else if !enabled {
                 proxy_wasm::hostcalls::log(LogLevel::Warn, &format!("Feature '{}' not available in current license", feature)).ok();
-                self.send_http_response(
+                let (code, reason) = if expired {
+                    (LicenseErrorCode::LicenseExpired, "license_expired")
+                } else if self.downgraded {
+                    (LicenseErrorCode::LicenseDowngraded, "license_downgraded")
+                } else {
+                    (LicenseErrorCode::FeatureGated, "feature_gated")
+                };
+                return self.reject(
                     402,
-                    vec![
-                        ("content-type", "application/json"),
-                        ("x-license-required", "enterprise"),
-                    ],
-                    Some(format!(
-                        "{{\"error\":\"Enterprise license required for feature: {}\",\"upgrade_url\":\"https://marchproxy.penguintech.io/pricing\"}}",
-                        feature
-                    ).as_bytes()),
+                    code,
+                    reason,
+                    vec![("x-license-required", "enterprise")],
+                    serde_json::json!({
+                        "error": format!("Enterprise license required for feature: {}", feature),
+                        "upgrade_url": "https://marchproxy.penguintech.io/pricing",
+                    }),
                 );
-                return Action::Pause;
             }
         }
 
         // Check proxy count limit
-        if self.config.current_proxies > self.config.max_proxies {
+        let exceeded = self.config.current_proxies > self.config.max_proxies;
+        if exceeded && self.config.limit_action == LimitAction::WarnOnly {
+            proxy_wasm::hostcalls::log(
+                LogLevel::Warn,
+                &format!("Proxy count ({}) exceeds license limit ({}), continuing in warn-only mode",
+                        self.config.current_proxies, self.config.max_proxies)
+            ).ok();
+            self.over_limit = true;
+            increment_metric("marchproxy_license_over_limit_total", 1);
+            self.set_http_request_header("x-license-over-limit", Some("true"));
+        } else if exceeded {
             proxy_wasm::hostcalls::log(
                 LogLevel::Error,
                 &format!("Proxy count ({}) exceeds license limit ({})",
                         self.config.current_proxies, self.config.max_proxies)
             ).ok();
-            self.send_http_response(
+            return self.reject(
                 429,
-                vec![
-                    ("content-type", "application/json"),
-                    ("x-license-limit-exceeded", "true"),
-                ],
-                Some(format!(
-                    "{{\"error\":\"Proxy count limit exceeded\",\"current\":{},\"limit\":{},\"upgrade_url\":\"https://marchproxy.penguintech.io/pricing\"}}",
-                    self.config.current_proxies, self.config.max_proxies
-                ).as_bytes()),
+                LicenseErrorCode::ProxyLimit,
+                "proxy_limit",
+                vec![("x-license-limit-exceeded", "true")],
+                serde_json::json!({
+                    "error": "Proxy count limit exceeded",
+                    "current": self.config.current_proxies,
+                    "limit": self.config.max_proxies,
+                    "upgrade_url": "https://marchproxy.penguintech.io/pricing",
+                }),
             );
-            return Action::Pause;
+        }
+
+        // Per-client monthly quotas; clients without a quota are not counted
+        let client_id = self.get_http_request_header(CLIENT_ID_HEADER).filter(|id| !id.is_empty());
+        if let Some(quota) = self.config.quota_for(client_id.as_deref()) {
+            let (used, resets_at) = self.consume_client_quota(client_id.as_deref());
+            if used > quota {
+                proxy_wasm::hostcalls::log(
+                    LogLevel::Warn,
+                    &format!("Client '{}' exceeded its quota of {} requests",
+                            client_id.as_deref().unwrap_or(ANONYMOUS_BUCKET), quota),
+                ).ok();
+                let resets_at = resets_at.to_string();
+                return self.reject(
+                    429,
+                    LicenseErrorCode::QuotaExceeded,
+                    "client_quota",
+                    vec![("x-quota-reset", resets_at.as_str())],
+                    serde_json::json!({
+                        "error": "Client quota exceeded",
+                        "client_id": client_id,
+                        "quota": quota,
+                        "resets_at": resets_at,
+                    }),
+                );
+            }
         }
 
         // Add license information to request headers
         self.set_http_request_header("x-license-edition",
-                                    Some(if self.config.is_enterprise { "enterprise" } else { "community" }));
+                                    Some(self.config.edition()));
         self.set_http_request_header("x-license-key", Some(&self.config.license_key));
 
         Action::Continue
     }
 
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        enter_hook("on_http_response_headers");
         // Add license information to response headers
         self.set_http_response_header("x-marchproxy-edition",
-                                     Some(if self.config.is_enterprise { "enterprise" } else { "community" }));
+                                     Some(self.config.edition()));
+        if self.over_limit {
+            self.set_http_response_header("x-license-over-limit", Some("true"));
+        }
 
         Action::Continue
     }
 }
 
 impl LicenseFilter {
+    /// With `enable_config_dump`, answers a request for `config_dump_path` carrying the
+    /// `x-marchproxy-config-secret` header with the effective config as JSON, secrets redacted.
+    /// Without the right secret the request is handled like any other.
+    fn serve_config_dump(&mut self) -> Option<Action> {
+        if !self.config.enable_config_dump {
+            return None;
+        }
+        let path = self.get_http_request_header(":path")?;
+        if path.split('?').next() != Some(self.config.config_dump_path.as_str()) {
+            return None;
+        }
+        let secret = self.get_http_request_header(CONFIG_DUMP_SECRET_HEADER)?;
+        if !secret_eq(&secret, &self.config.config_dump_secret) {
+            proxy_wasm::hostcalls::log(LogLevel::Warn, "Config dump requested with a wrong secret").ok();
+            return None;
+        }
+
+        let mut config = serde_json::to_value(&self.config).ok()?;
+        redact_secrets(&mut config, &SECRET_FIELDS);
+        proxy_wasm::hostcalls::log(LogLevel::Info, "Serving license filter config dump").ok();
+        self.send_http_response(
+            200,
+            vec![("content-type", "application/json"), ("cache-control", "no-store")],
+            Some(config.to_string().as_bytes()),
+        );
+        Some(Action::Pause)
+    }
+
     fn get_required_feature(&self, path: &str) -> Option<String> {
         // Map paths to required enterprise features
-        if path.starts_with("/api/v1/traffic-shaping") {
-            Some("advanced_routing".to_string())
-        } else if path.starts_with("/api/v1/multi-cloud") {
-            Some("multi_cloud".to_string())
-        } else if path.starts_with("/api/v1/tracing") {
-            Some("distributed_tracing".to_string())
-        } else if path.starts_with("/api/v1/zero-trust") {
-            Some("zero_trust".to_string())
-        } else if path.starts_with("/api/v1/advanced-rate-limit") {
-            Some("rate_limiting".to_string())
-        } else {
-            None
+        FEATURE_PATHS.iter()
+            .find(|(prefix, _)| path.starts_with(prefix))
+            .map(|(_, feature)| feature.to_string())
+    }
+
+    /// Switches to the feature set licensed for the request's `:authority` host, or failing that
+    /// its TLS SNI. Other hosts keep the global `features`.
+    fn select_host_features(&mut self) {
+        if self.config.features_by_sni.is_empty() {
+            return;
+        }
+
+        let authority = self.get_http_request_header(":authority");
+        let sni = self.get_property(vec!["connection", "requested_server_name"])
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+
+        let features = host_features(&self.config.features_by_sni, authority.as_deref(), sni.as_deref()).cloned();
+        if let Some(features) = features {
+            self.config.features = features;
+        }
+    }
+
+    /// Counts one request against the client's quota (or the shared anonymous bucket) for the
+    /// current UTC month in shared data, returning the month's usage including this request and
+    /// the Unix time the count resets.
+    fn consume_client_quota(&self, client_id: Option<&str>) -> (u64, u64) {
+        let now = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default().as_secs();
+        let (key, resets_at) = quota_window(client_id, now);
+
+        // Retry CAS conflicts with other workers; on persistent contention count without storing
+        let mut used = 1;
+        for _ in 0..5 {
+            let (data, cas) = self.get_shared_data(&key);
+            used = next_quota_count(data);
+            if self.set_shared_data(&key, Some(&used.to_le_bytes()), cas).is_ok() {
+                break;
+            }
         }
+        (used, resets_at)
     }
 
+    /// A feature is enabled when licensed (or overridden for this request) and every feature
+    /// it depends on is enabled too. An override unlocks only the named feature, not its
+    /// dependencies.
     fn is_feature_enabled(&self, feature: &str) -> bool {
-        self.config.features.get(feature).copied().unwrap_or(false)
+        let enabled = self.override_feature.as_deref() == Some(feature)
+            || self.config.features.get(feature).copied().unwrap_or(false);
+        // Cycles are refused in on_configure, so the recursion ends
+        enabled && self.config.feature_dependencies.get(feature)
+            .map(|dependencies| dependencies.iter().all(|dependency| self.is_feature_enabled(dependency)))
+            .unwrap_or(true)
+    }
+
+    /// Verifies an override token of the form `<feature>:<expires_unix>:<hex hmac>`, where the
+    /// HMAC-SHA256 over `<feature>:<expires_unix>` is keyed with `override_secret`. Returns the
+    /// feature it unlocks for this request.
+    fn verify_override(&self, token: &str) -> Option<String> {
+        let verified = (|| {
+            let (payload, signature) = token.rsplit_once(':')?;
+            let (feature, expires) = payload.split_once(':')?;
+            let expires: u64 = expires.parse().ok()?;
+
+            let now = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default().as_secs();
+            if now >= expires {
+                return None;
+            }
+
+            let mut mac = Hmac::<Sha256>::new_from_slice(self.config.override_secret.as_bytes()).ok()?;
+            mac.update(payload.as_bytes());
+            mac.verify_slice(&decode_hex(signature)?).ok()?;
+            Some(feature.to_string())
+        })();
+
+        match &verified {
+            Some(feature) => {
+                proxy_wasm::hostcalls::log(LogLevel::Warn, &format!("Feature override accepted for '{}'", feature)).ok();
+                increment_metric("marchproxy_license_override_total", 1);
+            }
+            None => {
+                proxy_wasm::hostcalls::log(LogLevel::Warn, "Rejected invalid or expired feature override").ok();
+                increment_metric("marchproxy_license_override_invalid_total", 1);
+            }
+        }
+        verified
+    }
+
+    /// Sends the JSON rejection response, tagged with `code` in both the body and the
+    /// `x-license-error-code` header, and records which filter rejected the request and why,
+    /// both as `marchproxy.rejected_by`/`marchproxy.rejection_reason` filter state and as metrics.
+    fn reject(
+        &self,
+        status: u32,
+        code: LicenseErrorCode,
+        reason: &str,
+        headers: Vec<(&str, &str)>,
+        body: serde_json::Value,
+    ) -> Action {
+        record_rejection("license", reason);
+
+        let (headers, body) = rejection_response(code, headers, body);
+        self.send_http_response(status, headers, Some(body.as_bytes()));
+        Action::Pause
+    }
+}
+
+/// Headers and serialized body of a license rejection: `code` is added to the body as
+/// `error_code` and sent as `x-license-error-code`, next to the JSON content type.
+fn rejection_response<'a>(
+    code: LicenseErrorCode,
+    mut headers: Vec<(&'a str, &'a str)>,
+    mut body: serde_json::Value,
+) -> (Vec<(&'a str, &'a str)>, String) {
+    body["error_code"] = serde_json::Value::from(code.as_str());
+    headers.push(("content-type", "application/json"));
+    headers.push(("x-license-error-code", code.as_str()));
+    (headers, body.to_string())
+}
+
+/// The feature set `features_by_sni` licenses for the `:authority` host, or failing that the
+/// TLS SNI, matched case-insensitively. None means the global `features` apply.
+fn host_features<'a>(
+    features_by_sni: &'a HashMap<String, HashMap<String, bool>>,
+    authority: Option<&str>,
+    sni: Option<&str>,
+) -> Option<&'a HashMap<String, bool>> {
+    authority.and_then(|authority| features_by_sni.get(&authority_host(authority).to_lowercase()))
+        .or_else(|| sni.and_then(|sni| features_by_sni.get(&sni.to_lowercase())))
+}
+
+/// Shared-data key for a client's quota count in the UTC month containing `now`, and the Unix
+/// time that month ends. Requests without a verified client id all share one bucket.
+fn quota_window(client_id: Option<&str>, now: u64) -> (String, u64) {
+    let (year, month) = year_month(now);
+    let resets_at = if month == 12 { month_start(year + 1, 1) } else { month_start(year, month + 1) };
+    // Distinct prefixes keep a client named like the anonymous bucket out of it
+    let bucket = match client_id {
+        Some(client_id) => format!("client_{}", client_id),
+        None => String::from(ANONYMOUS_BUCKET),
+    };
+    (format!("marchproxy_license_quota_{}_{:04}{:02}", bucket, year, month), resets_at)
+}
+
+/// The quota count after this request, from the stored little-endian count (absent or
+/// malformed counts start over).
+fn next_quota_count(stored: Option<Bytes>) -> u64 {
+    stored
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or(0) + 1
+}
+
+/// The UTC (year, month) containing `unix_seconds`.
+fn year_month(unix_seconds: u64) -> (i64, u32) {
+    // Civil-from-days conversion over 400-year eras (Howard Hinnant's algorithm)
+    let days = (unix_seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month)
+}
+
+/// Unix time of midnight UTC on the first day of `month` in `year`.
+fn month_start(year: i64, month: u32) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    (days.max(0) as u64) * 86_400
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    value.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some(((*high as char).to_digit(16)? * 16 + (*low as char).to_digit(16)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A feature that, through `feature_dependencies`, ends up depending on itself.
+fn find_dependency_cycle(dependencies: &HashMap<String, Vec<String>>) -> Option<String> {
+    fn visit<'a>(
+        feature: &'a str,
+        dependencies: &'a HashMap<String, Vec<String>>,
+        path: &mut Vec<&'a str>,
+        done: &mut Vec<&'a str>,
+    ) -> Option<String> {
+        if path.contains(&feature) {
+            return Some(feature.to_string());
+        }
+        if done.contains(&feature) {
+            return None;
+        }
+        path.push(feature);
+        for dependency in dependencies.get(feature).into_iter().flatten() {
+            if let Some(cycle) = visit(dependency, dependencies, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.push(feature);
+        None
+    }
+
+    let mut done = Vec::new();
+    dependencies.keys().find_map(|feature| visit(feature, dependencies, &mut Vec::new(), &mut done))
+}
+
+/// Checks the global and per-host feature maps for every feature a gated path requires and
+/// for enabled features whose dependencies are off, returning a warning for each. An unknown
+/// feature is always gated off, which usually means a typo, so with `strict_features` a
+/// missing feature is an error instead. A dependency cycle is always an error.
+fn check_feature_maps(config: &FilterConfig) -> Result<Vec<String>, String> {
+    if let Some(feature) = find_dependency_cycle(&config.feature_dependencies) {
+        return Err(format!("Feature '{}' depends on itself through feature_dependencies", feature));
+    }
+
+    let mut warnings = Vec::new();
+    let feature_maps = std::iter::once(("global", &config.features))
+        .chain(config.features_by_sni.iter().map(|(host, features)| (host.as_str(), features)));
+    for (scope, features) in feature_maps {
+        for (_, feature) in FEATURE_PATHS {
+            if features.contains_key(feature) {
+                continue;
+            }
+            if config.strict_features {
+                return Err(format!("Feature '{}' is missing from the {} features map", feature, scope));
+            }
+            warnings.push(format!("Feature '{}' is missing from the {} features map and will be gated", feature, scope));
+        }
+        for (feature, dependencies) in &config.feature_dependencies {
+            if !features.get(feature).copied().unwrap_or(false) {
+                continue;
+            }
+            for dependency in dependencies.iter().filter(|dependency| !features.get(*dependency).copied().unwrap_or(false)) {
+                warnings.push(format!(
+                    "Feature '{}' is enabled in the {} features map but its dependency '{}' is not, so it will be gated",
+                    feature, scope, dependency,
+                ));
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+/// Config fields holding secrets. The config dump replaces them wherever they occur.
+const SECRET_FIELDS: [&str; 3] = ["license_key", "override_secret", "config_dump_secret"];
+
+/// Parses plugin configuration, also returning which of `DEFAULTED_FIELDS` were absent and so
+/// took their Community defaults.
+fn parse_license_config(bytes: &[u8]) -> Result<(FilterConfig, Vec<&'static str>), String> {
+    let value: serde_json::Value = parse_config(bytes)?;
+    let defaulted = DEFAULTED_FIELDS.into_iter()
+        .filter(|field| value.get(field).is_none())
+        .collect();
+    let config = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    Ok((config, defaulted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Community feature set with `zero_trust` misspelled.
+    fn misspelled_features() -> HashMap<String, bool> {
+        let mut features = default_features();
+        features.remove("zero_trust");
+        features.insert(String::from("zero_trst"), true);
+        features
+    }
+
+    fn filter_with(config: FilterConfig) -> LicenseFilter {
+        LicenseFilter { config, downgraded: false, over_limit: false, override_feature: None }
+    }
+
+    fn features(enabled: &[&str]) -> HashMap<String, bool> {
+        let mut features = default_features();
+        for feature in enabled {
+            features.insert(feature.to_string(), true);
+        }
+        features
+    }
+
+    #[test]
+    fn hosts_get_their_own_feature_sets() {
+        let features_by_sni = HashMap::from([
+            (String::from("acme.example.com"), features(&["zero_trust"])),
+            (String::from("globex.example.com"), features(&["multi_cloud"])),
+        ]);
+        let gate = |authority: Option<&str>, sni: Option<&str>, feature: &str| {
+            let features = host_features(&features_by_sni, authority, sni).cloned().unwrap_or_else(default_features);
+            filter_with(FilterConfig { features, ..FilterConfig::default() }).is_feature_enabled(feature)
+        };
+
+        assert!(gate(Some("acme.example.com"), None, "zero_trust"));
+        assert!(!gate(Some("acme.example.com"), None, "multi_cloud"));
+        assert!(gate(Some("Globex.Example.com:8443"), None, "multi_cloud"));
+        assert!(!gate(Some("globex.example.com"), None, "zero_trust"));
+
+        // SNI is only consulted when the authority matches no host
+        assert!(gate(Some("10.0.0.1"), Some("acme.example.com"), "zero_trust"));
+        assert!(gate(Some("globex.example.com"), Some("acme.example.com"), "multi_cloud"));
+
+        // Unknown hosts keep the global features
+        assert!(host_features(&features_by_sni, Some("other.example.com"), None).is_none());
+        assert!(!gate(Some("other.example.com"), None, "zero_trust"));
+    }
+
+    #[test]
+    fn edition_header_follows_the_license() {
+        let community = FilterConfig::default();
+        assert_eq!(community.edition(), "community");
+        let enterprise = FilterConfig { is_enterprise: true, ..FilterConfig::default() };
+        assert_eq!(enterprise.edition(), "enterprise");
+    }
+
+    #[test]
+    fn quotas_are_keyed_by_verified_client_per_month() {
+        let config = FilterConfig {
+            client_quotas: HashMap::from([(String::from("billing-service"), 100_000)]),
+            anonymous_quota: Some(1_000),
+            ..FilterConfig::default()
+        };
+        assert_eq!(config.quota_for(Some("billing-service")), Some(100_000));
+        assert_eq!(config.quota_for(None), Some(1_000));
+        // Verified clients without a quota are not counted, even with an anonymous quota
+        assert_eq!(config.quota_for(Some("reporting")), None);
+
+        // 2026-10-16T12:00:00Z
+        let now = 1_792_152_000;
+        let (key, resets_at) = quota_window(Some("billing-service"), now);
+        assert_eq!(key, "marchproxy_license_quota_client_billing-service_202610");
+        assert_eq!(resets_at, 1_793_491_200);
+        // December rolls over into January
+        let (key, resets_at) = quota_window(Some("billing-service"), 1_797_336_000);
+        assert_eq!(key, "marchproxy_license_quota_client_billing-service_202612");
+        assert_eq!(resets_at, 1_798_761_600);
+    }
+
+    #[test]
+    fn requests_without_a_client_id_share_one_bucket() {
+        let now = 1_792_152_000;
+        let (shared, _) = quota_window(None, now);
+        assert_eq!(shared, "marchproxy_license_quota_anonymous_202610");
+        // A client named like the bucket still gets its own count
+        assert_ne!(quota_window(Some(ANONYMOUS_BUCKET), now).0, shared);
+        assert_eq!(FilterConfig::default().quota_for(None), None);
+    }
+
+    #[test]
+    fn quota_counts_start_over_when_absent_or_malformed() {
+        assert_eq!(next_quota_count(None), 1);
+        assert_eq!(next_quota_count(Some(41u64.to_le_bytes().to_vec())), 42);
+        assert_eq!(next_quota_count(Some(vec![1, 2, 3])), 1);
+    }
+
+    #[test]
+    fn license_key_alone_parses_with_community_defaults() {
+        let (config, defaulted) = parse_license_config(br#"{"license_key": "PENG-1234-5678"}"#).unwrap();
+        assert_eq!(defaulted, DEFAULTED_FIELDS.to_vec());
+        assert_eq!(config.license_key, "PENG-1234-5678");
+        assert!(!config.is_enterprise);
+        assert_eq!(config.features, default_features());
+        assert_eq!(config.max_proxies, default_max_proxies());
+        assert_eq!(config.current_proxies, 0);
+    }
+
+    #[test]
+    fn only_absent_fields_are_reported_as_defaulted() {
+        let (config, defaulted) = parse_license_config(br#"{"license_key": "PENG-1", "is_enterprise": true, "max_proxies": 50}"#).unwrap();
+        assert_eq!(defaulted, vec!["features", "current_proxies"]);
+        assert!(config.is_enterprise);
+        assert_eq!(config.max_proxies, 50);
+        assert!(parse_license_config(b"{}").is_err());
+    }
+
+    fn rejection(code: LicenseErrorCode, headers: Vec<(&str, &str)>, body: serde_json::Value) -> (Vec<(String, String)>, serde_json::Value) {
+        let (headers, body) = rejection_response(code, headers, body);
+        let headers = headers.into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        (headers, serde_json::from_str(&body).unwrap())
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn feature_gated_rejection_carries_its_code() {
+        let (headers, body) = rejection(
+            LicenseErrorCode::FeatureGated,
+            vec![("x-license-required", "enterprise")],
+            serde_json::json!({"error": "Enterprise license required for feature: zero_trust"}),
+        );
+        assert_eq!(body["error_code"], "FEATURE_GATED");
+        assert_eq!(body["error"], "Enterprise license required for feature: zero_trust");
+        assert_eq!(header(&headers, "x-license-error-code"), Some("FEATURE_GATED"));
+        assert_eq!(header(&headers, "x-license-required"), Some("enterprise"));
+        assert_eq!(header(&headers, "content-type"), Some("application/json"));
+    }
+
+    #[test]
+    fn every_rejection_path_sets_body_and_header_codes() {
+        let paths = [
+            (LicenseErrorCode::LicenseDowngraded, "LICENSE_DOWNGRADED", ("x-license-required", "enterprise")),
+            (LicenseErrorCode::LicenseExpired, "LICENSE_EXPIRED", ("x-license-required", "enterprise")),
+            (LicenseErrorCode::ProxyLimit, "PROXY_LIMIT", ("x-license-limit-exceeded", "true")),
+            (LicenseErrorCode::QuotaExceeded, "QUOTA_EXCEEDED", ("x-quota-reset", "1767225600")),
+        ];
+        for (code, expected, extra) in paths {
+            let (headers, body) = rejection(code, vec![extra], serde_json::json!({"error": "rejected"}));
+            assert_eq!(body["error_code"], expected);
+            assert_eq!(header(&headers, "x-license-error-code"), Some(expected));
+            assert_eq!(header(&headers, extra.0), Some(extra.1));
+            assert_eq!(header(&headers, "content-type"), Some("application/json"));
+        }
+
+        let (headers, body) = rejection(LicenseErrorCode::Maintenance, Vec::new(), serde_json::json!({"error": "maintenance"}));
+        assert_eq!(body["error_code"], "MAINTENANCE");
+        assert_eq!(header(&headers, "x-license-error-code"), Some("MAINTENANCE"));
+    }
+
+    #[test]
+    fn license_expires_at_its_timestamp() {
+        let config = FilterConfig { license_expires_at: Some(1_798_761_600), ..FilterConfig::default() };
+        assert!(!config.is_expired(1_798_761_599));
+        assert!(config.is_expired(1_798_761_600));
+        assert!(config.is_expired(1_800_000_000));
+        assert!(!FilterConfig::default().is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn complete_feature_maps_pass() {
+        assert_eq!(check_feature_maps(&FilterConfig::default()), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn misspelled_feature_is_warned_about() {
+        let config = FilterConfig { features: misspelled_features(), ..FilterConfig::default() };
+        assert_eq!(
+            check_feature_maps(&config),
+            Ok(vec![String::from("Feature 'zero_trust' is missing from the global features map and will be gated")]),
+        );
+    }
+
+    #[test]
+    fn misspelled_feature_is_rejected_when_strict() {
+        let config = FilterConfig { features: misspelled_features(), strict_features: true, ..FilterConfig::default() };
+        assert_eq!(check_feature_maps(&config), Err(String::from("Feature 'zero_trust' is missing from the global features map")));
+
+        let config = FilterConfig {
+            features_by_sni: HashMap::from([(String::from("tenant.example.com"), misspelled_features())]),
+            strict_features: true,
+            ..FilterConfig::default()
+        };
+        assert_eq!(
+            check_feature_maps(&config),
+            Err(String::from("Feature 'zero_trust' is missing from the tenant.example.com features map")),
+        );
+    }
+
+    fn zero_trust_requires_advanced_routing() -> HashMap<String, Vec<String>> {
+        HashMap::from([(String::from("zero_trust"), vec![String::from("advanced_routing")])])
+    }
+
+    #[test]
+    fn feature_with_disabled_dependency_is_gated() {
+        let config = FilterConfig {
+            features: features(&["zero_trust"]),
+            feature_dependencies: zero_trust_requires_advanced_routing(),
+            ..FilterConfig::default()
+        };
+        assert_eq!(
+            check_feature_maps(&config),
+            Ok(vec![String::from(
+                "Feature 'zero_trust' is enabled in the global features map but its dependency 'advanced_routing' is not, so it will be gated",
+            )]),
+        );
+        let mut filter = filter_with(config);
+        assert!(!filter.is_feature_enabled("zero_trust"));
+
+        // An override unlocks the named feature only, not what it depends on
+        filter.override_feature = Some(String::from("advanced_routing"));
+        assert!(filter.is_feature_enabled("zero_trust"));
+        filter.override_feature = Some(String::from("zero_trust"));
+        filter.config.features = default_features();
+        assert!(!filter.is_feature_enabled("zero_trust"));
+    }
+
+    #[test]
+    fn feature_with_enabled_dependencies_is_allowed() {
+        let config = FilterConfig {
+            features: features(&["zero_trust", "advanced_routing"]),
+            feature_dependencies: zero_trust_requires_advanced_routing(),
+            ..FilterConfig::default()
+        };
+        assert_eq!(check_feature_maps(&config), Ok(Vec::new()));
+        assert!(filter_with(config).is_feature_enabled("zero_trust"));
+    }
+
+    #[test]
+    fn dependency_cycle_is_refused() {
+        let config = FilterConfig {
+            feature_dependencies: HashMap::from([
+                (String::from("zero_trust"), vec![String::from("advanced_routing")]),
+                (String::from("advanced_routing"), vec![String::from("multi_cloud")]),
+                (String::from("multi_cloud"), vec![String::from("zero_trust")]),
+            ]),
+            ..FilterConfig::default()
+        };
+        let error = check_feature_maps(&config).unwrap_err();
+        assert!(error.ends_with("depends on itself through feature_dependencies"), "unexpected error: {}", error);
+        assert!(find_dependency_cycle(&zero_trust_requires_advanced_routing()).is_none());
+        assert!(find_dependency_cycle(&HashMap::from([(String::from("a"), vec![String::from("a")])])).is_some());
     }
 }
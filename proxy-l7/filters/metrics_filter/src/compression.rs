@@ -0,0 +1,136 @@
+// Streaming gzip probe measuring how far a compressed response body expands
+
+use flate2::write::GzDecoder;
+use std::io::{self, Write};
+
+/// Discards decompressed output, counting it and refusing more than `max_bytes` so a
+/// decompression bomb stops costing CPU as soon as it crosses the cap.
+struct CountingSink {
+    written: usize,
+    max_bytes: usize,
+}
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() > self.max_bytes {
+            self.written = self.max_bytes;
+            return Err(io::Error::other("decompressed size cap reached"));
+        }
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses a gzip body chunk by chunk without keeping it, tracking compressed and
+/// decompressed sizes. Stops early once the decompressed size reaches the cap, in which case
+/// the ratio is a lower bound.
+pub struct CompressionProbe {
+    decoder: GzDecoder<CountingSink>,
+    compressed: usize,
+    capped: bool,
+    corrupt: bool,
+}
+
+impl CompressionProbe {
+    pub fn new(max_decompressed_bytes: usize) -> Self {
+        Self {
+            decoder: GzDecoder::new(CountingSink { written: 0, max_bytes: max_decompressed_bytes }),
+            compressed: 0,
+            capped: false,
+            corrupt: false,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        if self.is_stopped() {
+            return;
+        }
+        self.compressed += chunk.len();
+        if self.decoder.write_all(chunk).is_err() {
+            self.mark_stopped();
+        }
+    }
+
+    /// True once further chunks would not change the outcome.
+    pub fn is_stopped(&self) -> bool {
+        self.capped || self.corrupt
+    }
+
+    /// Decompressed-to-compressed ratio, rounded down; None for an invalid or empty stream.
+    pub fn finish(mut self) -> Option<u64> {
+        if !self.is_stopped() && self.decoder.try_finish().is_err() {
+            self.mark_stopped();
+        }
+        if self.corrupt || self.compressed == 0 {
+            return None;
+        }
+        Some((self.decoder.get_ref().written / self.compressed) as u64)
+    }
+
+    fn mark_stopped(&mut self) {
+        let sink = self.decoder.get_ref();
+        if sink.written >= sink.max_bytes {
+            self.capped = true;
+        } else {
+            self.corrupt = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_body_reports_its_ratio() {
+        let body = gzip(&[b'a'; 64 * 1024]);
+        let mut probe = CompressionProbe::new(1 << 20);
+        for chunk in body.chunks(7) {
+            probe.feed(chunk);
+        }
+        assert!(!probe.is_stopped());
+        assert_eq!(probe.finish(), Some((64 * 1024 / body.len()) as u64));
+    }
+
+    #[test]
+    fn non_gzip_body_has_no_ratio() {
+        let mut probe = CompressionProbe::new(1 << 20);
+        probe.feed(b"{\"plain\":\"json, not gzip\"}");
+        assert!(probe.is_stopped());
+        assert_eq!(probe.finish(), None);
+    }
+
+    #[test]
+    fn truncated_gzip_header_has_no_ratio() {
+        let body = gzip(b"hello");
+        let mut probe = CompressionProbe::new(1 << 20);
+        probe.feed(&body[..5]);
+        assert_eq!(probe.finish(), None);
+    }
+
+    #[test]
+    fn capped_body_reports_a_lower_bound() {
+        let body = gzip(&[0; 1 << 20]);
+        let mut probe = CompressionProbe::new(4096);
+        probe.feed(&body);
+        assert!(probe.is_stopped());
+        assert_eq!(probe.finish(), Some((4096 / body.len()) as u64));
+    }
+
+    #[test]
+    fn empty_body_has_no_ratio() {
+        assert_eq!(CompressionProbe::new(1 << 20).finish(), None);
+    }
+}
@@ -1,15 +1,35 @@
 // MarchProxy Metrics Filter (WASM)
 // Custom metrics collection for MarchProxy
 
+mod compression;
+mod prometheus;
+mod stats;
+
+use base64::Engine;
+use compression::CompressionProbe;
+use marchproxy_filter_common::body::{default_max_buffer_bytes, BodyBuffer};
+use marchproxy_filter_common::panic::{enter_hook, install_panic_hook};
+use marchproxy_filter_common::secrets::{redact_secrets, secret_eq};
+use marchproxy_filter_common::{authority_host, check_filter_position, parse_config};
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
 use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
 
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Info);
+    install_panic_hook("metrics");
     proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
         Box::new(MetricsFilterRoot {
             config: FilterConfig::default(),
+            registry: Rc::new(RefCell::new(prometheus::Registry::new(default_max_metric_series()))),
+            stats: Rc::new(RefCell::new(stats::EnvoyStats::new(default_max_metric_series()))),
+            rate: RequestRate::default(),
+            scale_carry: Rc::new(RefCell::new(HashMap::new())),
+            latency_reservoir: Rc::new(RefCell::new(Reservoir::new(0x5EED))),
+            summary_gauges: HashMap::new(),
         })
     });
 }}
@@ -21,6 +41,453 @@ struct FilterConfig {
     enable_timing_metrics: bool,
     enable_size_metrics: bool,
     sample_rate: f32,
+    #[serde(default)]
+    duration_unit: DurationUnit,
+    #[serde(default)]
+    enable_body_error_detection: bool,
+    #[serde(default = "default_max_buffer_bytes", alias = "max_inspect_bytes")]
+    max_buffer_bytes: usize,
+    #[serde(default = "default_error_marker_field")]
+    error_marker_field: String,
+    #[serde(default)]
+    required_headers_metric: Vec<String>,
+    #[serde(default)]
+    warmup_sample_count: u64,
+    #[serde(default)]
+    dual_emit_prefix: Option<String>,
+    #[serde(default = "default_dual_emit_rate")]
+    dual_emit_rate: f32,
+    #[serde(default)]
+    enable_apdex: bool,
+    #[serde(default = "default_apdex_target_ms")]
+    apdex_target_ms: u64,
+    #[serde(default)]
+    serve_prometheus: bool,
+    #[serde(default = "default_metrics_path")]
+    metrics_path: String,
+    #[serde(default)]
+    duration_by_status_class: bool,
+    #[serde(default)]
+    slo_latency_threshold_ms: Option<u64>,
+    #[serde(default)]
+    slo_error_is_5xx: bool,
+    #[serde(default)]
+    slo_routes: Vec<SloRoute>,
+    #[serde(default)]
+    enable_protocol_metrics: bool,
+    #[serde(default)]
+    capture_headers: Vec<String>,
+    #[serde(default)]
+    rps_tick_seconds: u64,
+    #[serde(default)]
+    expected_position: Option<usize>,
+    #[serde(default)]
+    force_sample_header: Option<String>,
+    #[serde(default)]
+    force_sample_secret: Option<String>,
+    #[serde(default)]
+    metrics_route_rules: Vec<MetricsRouteRule>,
+    #[serde(default)]
+    enable_compression_ratio_metrics: bool,
+    #[serde(default = "default_compression_ratio_threshold")]
+    compression_ratio_threshold: u64,
+    #[serde(default = "default_max_decompressed_bytes")]
+    max_decompressed_bytes: usize,
+    #[serde(default)]
+    scale_by_sample_rate: bool,
+    #[serde(default)]
+    histogram_percentiles: Vec<f64>,
+    #[serde(default)]
+    enterprise_sample_rate: Option<f32>,
+    #[serde(default)]
+    community_sample_rate: Option<f32>,
+    #[serde(default)]
+    enable_canary_metrics: bool,
+    #[serde(default = "default_canary_version_header")]
+    canary_version_header: String,
+    #[serde(default)]
+    canary_versions: Vec<String>,
+    #[serde(default)]
+    latency_metric_type: MetricKind,
+    #[serde(default = "default_summary_interval_seconds")]
+    summary_interval_seconds: u64,
+    #[serde(default)]
+    trust_method_override: bool,
+    #[serde(default)]
+    tenant_from: Option<TenantSource>,
+    #[serde(default)]
+    known_tenants: Vec<String>,
+    #[serde(default)]
+    known_path_prefixes: Vec<String>,
+    #[serde(default = "default_max_metric_series")]
+    max_metric_series: usize,
+    #[serde(default)]
+    enable_exemplars: bool,
+    #[serde(default)]
+    enable_retry_metrics: bool,
+    #[serde(default)]
+    enable_config_dump: bool,
+    #[serde(default = "default_config_dump_path")]
+    config_dump_path: String,
+    #[serde(default)]
+    config_dump_secret: String,
+}
+
+/// Headers whose values are never written to captured header logs.
+const SENSITIVE_HEADERS: [&str; 6] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-license-key",
+];
+
+/// Per-route SLO: requests under `prefix` count towards `marchproxy_slo_{good,bad}_by_route_<name>`
+/// against their own latency threshold instead of the global one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct SloRoute {
+    prefix: String,
+    name: String,
+    latency_threshold_ms: u64,
+}
+
+/// Overrides the global `enable_*_metrics` flags for requests under `prefix`; unset fields keep
+/// the global value. The longest matching prefix wins.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct MetricsRouteRule {
+    prefix: String,
+    #[serde(default)]
+    request: Option<bool>,
+    #[serde(default)]
+    response: Option<bool>,
+    #[serde(default)]
+    timing: Option<bool>,
+    #[serde(default)]
+    size: Option<bool>,
+}
+
+/// The metric families enabled for one request, resolved once from the global flags and any
+/// matching route rule.
+#[derive(Debug, Clone, Copy)]
+struct MetricsToggle {
+    request: bool,
+    response: bool,
+    timing: bool,
+    size: bool,
+}
+
+impl MetricsToggle {
+    fn from_config(config: &FilterConfig) -> Self {
+        Self {
+            request: config.enable_request_metrics,
+            response: config.enable_response_metrics,
+            timing: config.enable_timing_metrics,
+            size: config.enable_size_metrics,
+        }
+    }
+
+    /// The global flags overridden by the longest route rule whose prefix matches `path`.
+    fn for_path(config: &FilterConfig, path: &str) -> Self {
+        let toggle = Self::from_config(config);
+        match config.metrics_route_rules.iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+        {
+            Some(rule) => toggle.with_rule(rule),
+            None => toggle,
+        }
+    }
+
+    fn with_rule(self, rule: &MetricsRouteRule) -> Self {
+        Self {
+            request: rule.request.unwrap_or(self.request),
+            response: rule.response.unwrap_or(self.response),
+            timing: rule.timing.unwrap_or(self.timing),
+            size: rule.size.unwrap_or(self.size),
+        }
+    }
+}
+
+fn default_metrics_path() -> String {
+    String::from("/marchproxy/metrics")
+}
+
+fn default_apdex_target_ms() -> u64 {
+    500
+}
+
+fn default_dual_emit_rate() -> f32 {
+    1.0
+}
+
+fn default_max_metric_series() -> usize {
+    1000
+}
+
+fn default_config_dump_path() -> String {
+    String::from("/marchproxy/config/metrics")
+}
+
+fn default_summary_interval_seconds() -> u64 {
+    10
+}
+
+fn default_canary_version_header() -> String {
+    String::from("x-backend-version")
+}
+
+fn default_compression_ratio_threshold() -> u64 {
+    100
+}
+
+fn default_max_decompressed_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_error_marker_field() -> String {
+    String::from("error")
+}
+
+/// Unit used when recording request durations; also selects the metric name suffix.
+/// Envoy stats only hold integers, so with `Seconds` they get whole milliseconds under the `_ms`
+/// name, while the filter's own Prometheus output reports `_seconds` in seconds.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+enum DurationUnit {
+    #[default]
+    Milliseconds,
+    Microseconds,
+    Seconds,
+}
+
+impl DurationUnit {
+    /// The duration in this unit, for logs.
+    fn convert(&self, duration_ns: u64) -> f64 {
+        match self {
+            DurationUnit::Milliseconds => duration_ns as f64 / 1_000_000.0,
+            DurationUnit::Microseconds => duration_ns as f64 / 1_000.0,
+            DurationUnit::Seconds => duration_ns as f64 / 1_000_000_000.0,
+        }
+    }
+
+    /// The integer recorded in histograms and summaries: whole microseconds for
+    /// `Microseconds`, else whole milliseconds.
+    fn recorded(&self, duration_ns: u64) -> u64 {
+        match self {
+            DurationUnit::Milliseconds | DurationUnit::Seconds => duration_ns / 1_000_000,
+            DurationUnit::Microseconds => duration_ns / 1_000,
+        }
+    }
+
+    /// What a recorded value is divided by to read it in this unit.
+    fn divisor(&self) -> u64 {
+        match self {
+            DurationUnit::Seconds => 1_000,
+            DurationUnit::Milliseconds | DurationUnit::Microseconds => 1,
+        }
+    }
+
+    /// Name suffix of the Prometheus output, whose values are in this unit.
+    fn suffix(&self) -> &'static str {
+        match self {
+            DurationUnit::Milliseconds => "ms",
+            DurationUnit::Microseconds => "us",
+            DurationUnit::Seconds => "seconds",
+        }
+    }
+
+    /// Name suffix of the Envoy stats, whose values are the recorded integers.
+    fn stat_suffix(&self) -> &'static str {
+        match self {
+            DurationUnit::Milliseconds | DurationUnit::Seconds => "ms",
+            DurationUnit::Microseconds => "us",
+        }
+    }
+}
+
+/// How request durations are recorded. `Histogram` feeds Envoy's bucketed histograms;
+/// `Summary` keeps a reservoir per VM and publishes quantile gauges every
+/// `summary_interval_seconds`, for backends that prefer precomputed quantiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+enum MetricKind {
+    #[default]
+    Histogram,
+    Summary,
+}
+
+/// Where the tenant id for per-tenant metrics comes from, e.g. `"Authority"` or
+/// `{"Header": "x-tenant-id"}`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+enum TenantSource {
+    /// The `:authority` host, without port.
+    Authority,
+    Header(String),
+    /// A string claim of the bearer JWT, read without verifying it.
+    Claim(String),
+}
+
+/// Quantiles published for `Summary` latency when `histogram_percentiles` is empty.
+const DEFAULT_SUMMARY_PERCENTILES: [f64; 3] = [50.0, 90.0, 99.0];
+
+/// Durations kept per summary window; later ones replace kept ones at random.
+const RESERVOIR_SIZE: usize = 1024;
+
+/// Uniform sample of up to `RESERVOIR_SIZE` values from an unbounded stream (Algorithm R),
+/// so memory stays fixed however much traffic a window sees.
+struct Reservoir {
+    values: Vec<u64>,
+    seen: u64,
+    rng: SplitMix64,
+}
+
+impl Reservoir {
+    fn new(seed: u64) -> Self {
+        Self { values: Vec::with_capacity(RESERVOIR_SIZE), seen: 0, rng: SplitMix64::new(seed) }
+    }
+
+    fn observe(&mut self, value: u64) {
+        self.seen += 1;
+        if self.values.len() < RESERVOIR_SIZE {
+            self.values.push(value);
+        } else {
+            let slot = (self.rng.next_u64() % self.seen) as usize;
+            if slot < RESERVOIR_SIZE {
+                self.values[slot] = value;
+            }
+        }
+    }
+
+    /// The window's values at each percentile (nearest rank), emptying the reservoir.
+    fn drain_percentiles(&mut self, percentiles: &[f64]) -> Option<Vec<(f64, u64)>> {
+        if self.values.is_empty() {
+            return None;
+        }
+        let mut values = std::mem::take(&mut self.values);
+        self.seen = 0;
+        values.sort_unstable();
+        Some(percentiles.iter()
+            .map(|&percentile| {
+                let rank = ((percentile / 100.0) * values.len() as f64).ceil() as usize;
+                (percentile, values[rank.clamp(1, values.len()) - 1])
+            })
+            .collect())
+    }
+}
+
+/// The trace id of a W3C `traceparent` (`<version>-<trace id>-<parent id>-<flags>`), or None
+/// when it is malformed or the invalid all-zero id.
+fn parse_trace_id(traceparent: &str) -> Option<String> {
+    let trace_id = traceparent.trim().split('-').nth(1)?;
+    let valid = trace_id.len() == 32
+        && trace_id.bytes().all(|byte| byte.is_ascii_hexdigit())
+        && trace_id.bytes().any(|byte| byte != b'0');
+    valid.then(|| trace_id.to_ascii_lowercase())
+}
+
+/// Lower-cases `value` and replaces anything but ASCII letters and digits with `_`, so it is
+/// safe inside a metric name.
+fn metric_label(value: &str) -> String {
+    value.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+/// `value` as a metric label when it is one of `known` (matched case-insensitively, ignoring
+/// surrounding whitespace), else `other`.
+fn known_label(value: &str, known: &[String]) -> String {
+    let value = value.trim();
+    if !known.iter().any(|known| known.eq_ignore_ascii_case(value)) {
+        return String::from("other");
+    }
+    metric_label(value)
+}
+
+/// The first path segment as a metric label, sanitized; `root` for `/`. With `known` set, any
+/// segment not in it is reported as `other`, so clients cannot grow cardinality.
+fn path_label(path: &str, known: &[String]) -> String {
+    let segment = match path.split('?').next().unwrap_or_default().split('/').find(|s| !s.is_empty()) {
+        Some(segment) => segment,
+        None => return String::from("root"),
+    };
+    if !known.is_empty() && !known.iter().any(|prefix| prefix.trim_matches('/') == segment) {
+        return String::from("other");
+    }
+    segment.chars()
+        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+/// The request method as a metric label: a standard method in lower case, else `other`.
+fn method_label(method: &str) -> &'static str {
+    match method.to_ascii_uppercase().as_str() {
+        "GET" => "get",
+        "HEAD" => "head",
+        "POST" => "post",
+        "PUT" => "put",
+        "DELETE" => "delete",
+        "CONNECT" => "connect",
+        "OPTIONS" => "options",
+        "TRACE" => "trace",
+        "PATCH" => "patch",
+        _ => "other",
+    }
+}
+
+/// SplitMix64 PRNG: tiny, fast and uniform, which is all sampling decisions need.
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `[0, 1)` built from the top 53 bits.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn sample(&mut self, rate: f32) -> bool {
+        if rate >= 1.0 {
+            return true;
+        }
+        self.next_f64() < rate as f64
+    }
+}
+
+/// Nanoseconds from `start_ns` to `now_ns`, or None when the host clock has moved behind the
+/// start time (e.g. an NTP step), which would otherwise read as a huge or zero duration.
+fn elapsed_between(start_ns: u64, now_ns: u64) -> Option<u64> {
+    now_ns.checked_sub(start_ns)
+}
+
+/// Counter weight for a request sampled at `rate`: `1 / rate` with `scale_by_sample_rate` when
+/// it was randomly drawn, else 1. Warmup and forced samples do not stand in for others.
+fn sample_scale(config: &FilterConfig, rate: f32, drawn: bool) -> f64 {
+    if config.scale_by_sample_rate && drawn && rate > 0.0 {
+        1.0 / rate as f64
+    } else {
+        1.0
+    }
+}
+
+impl FilterConfig {
+    /// The configured sample rate for a license edition, if any; unknown editions keep the
+    /// global `sample_rate`.
+    fn sample_rate_for_edition(&self, edition: &str) -> Option<f32> {
+        match edition {
+            "enterprise" => self.enterprise_sample_rate,
+            "community" => self.community_sample_rate,
+            _ => None,
+        }
+    }
 }
 
 impl Default for FilterConfig {
@@ -31,22 +498,143 @@ impl Default for FilterConfig {
             enable_timing_metrics: true,
             enable_size_metrics: true,
             sample_rate: 1.0,
+            duration_unit: DurationUnit::default(),
+            enable_body_error_detection: false,
+            max_buffer_bytes: default_max_buffer_bytes(),
+            error_marker_field: default_error_marker_field(),
+            required_headers_metric: Vec::new(),
+            warmup_sample_count: 0,
+            dual_emit_prefix: None,
+            dual_emit_rate: default_dual_emit_rate(),
+            enable_apdex: false,
+            apdex_target_ms: default_apdex_target_ms(),
+            serve_prometheus: false,
+            metrics_path: default_metrics_path(),
+            duration_by_status_class: false,
+            slo_latency_threshold_ms: None,
+            slo_error_is_5xx: false,
+            slo_routes: Vec::new(),
+            enable_protocol_metrics: false,
+            capture_headers: Vec::new(),
+            rps_tick_seconds: 0,
+            expected_position: None,
+            force_sample_header: None,
+            force_sample_secret: None,
+            metrics_route_rules: Vec::new(),
+            enable_compression_ratio_metrics: false,
+            compression_ratio_threshold: default_compression_ratio_threshold(),
+            max_decompressed_bytes: default_max_decompressed_bytes(),
+            scale_by_sample_rate: false,
+            histogram_percentiles: Vec::new(),
+            enterprise_sample_rate: None,
+            community_sample_rate: None,
+            enable_canary_metrics: false,
+            canary_version_header: default_canary_version_header(),
+            canary_versions: Vec::new(),
+            latency_metric_type: MetricKind::default(),
+            summary_interval_seconds: default_summary_interval_seconds(),
+            trust_method_override: false,
+            tenant_from: None,
+            known_tenants: Vec::new(),
+            known_path_prefixes: Vec::new(),
+            max_metric_series: default_max_metric_series(),
+            enable_exemplars: false,
+            enable_retry_metrics: false,
+            enable_config_dump: false,
+            config_dump_path: default_config_dump_path(),
+            config_dump_secret: String::new(),
         }
     }
 }
 
+const WARMUP_COUNTER_KEY: &str = "marchproxy_metrics_warmup_count";
+
+/// Filter-state key where the auth filter records a trusted `X-HTTP-Method-Override`.
+const EFFECTIVE_METHOD_PROPERTY: &str = "marchproxy.effective_method";
+
+/// Header that must carry `config_dump_secret` for a config dump.
+const CONFIG_DUMP_SECRET_HEADER: &str = "x-marchproxy-config-secret";
+
 struct MetricsFilterRoot {
     config: FilterConfig,
+    registry: Rc<RefCell<prometheus::Registry>>,
+    stats: Rc<RefCell<stats::EnvoyStats>>,
+    rate: RequestRate,
+    /// Fractional counter increments not yet emitted, per metric, when scaling by sample rate.
+    scale_carry: Rc<RefCell<HashMap<String, f64>>>,
+    latency_reservoir: Rc<RefCell<Reservoir>>,
+    /// Gauge ids of the published summary quantiles, by metric name.
+    summary_gauges: HashMap<String, u32>,
 }
 
-impl Context for MetricsFilterRoot {}
+/// Per-VM state behind the `marchproxy_requests_per_second` gauge. Every worker VM adds its own
+/// rate to the shared gauge (by applying the change since its last tick), so the gauge reads
+/// the proxy-wide rate without any cross-VM coordination on the request path.
+#[derive(Default)]
+struct RequestRate {
+    requests: Cell<u64>,
+    last_requests: u64,
+    last_tick_ns: u64,
+    reported_rate: i64,
+    gauge_id: Option<u32>,
+}
+
+impl Context for MetricsFilterRoot {
+    fn on_done(&mut self) -> bool {
+        // Take this VM's share out of the gauge so a recycled VM does not leave it inflated
+        if let Some(gauge_id) = self.rate.gauge_id {
+            proxy_wasm::hostcalls::increment_metric(gauge_id, -self.rate.reported_rate).ok();
+            self.rate.reported_rate = 0;
+        }
+        true
+    }
+}
 
 impl RootContext for MetricsFilterRoot {
     fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        enter_hook("on_configure");
         if let Some(config_bytes) = self.get_plugin_configuration() {
-            match serde_json::from_slice::<FilterConfig>(&config_bytes) {
+            match parse_config::<FilterConfig>(&config_bytes) {
                 Ok(config) => {
+                    if let Some(invalid) = config.histogram_percentiles.iter().find(|p| !(**p > 0.0 && **p < 100.0)) {
+                        proxy_wasm::hostcalls::log(LogLevel::Error, &format!("Histogram percentile {} must be between 0 and 100 exclusive", invalid)).ok();
+                        return false;
+                    }
+                    if config.enable_config_dump && config.config_dump_secret.is_empty() {
+                        proxy_wasm::hostcalls::log(LogLevel::Error, "enable_config_dump requires a non-empty config_dump_secret").ok();
+                        return false;
+                    }
                     self.config = config;
+                    self.registry.borrow_mut().set_max_series(self.config.max_metric_series);
+                    self.stats.borrow_mut().set_max_series(self.config.max_metric_series);
+                    if !self.config.histogram_percentiles.is_empty() {
+                        // Envoy's own histogram quantiles come from its stats config, which Wasm cannot set
+                        proxy_wasm::hostcalls::log(LogLevel::Info, &format!("Histogram percentiles: {:?}", self.config.histogram_percentiles)).ok();
+                    }
+                    // Only the first configure in the process opens the warmup window; later pushes,
+                    // and other workers configuring after requests began, must not reopen it
+                    if self.config.warmup_sample_count > 0 {
+                        if let (None, _) = self.get_shared_data(WARMUP_COUNTER_KEY) {
+                            self.set_shared_data(WARMUP_COUNTER_KEY, Some(&0u64.to_le_bytes()), None).ok();
+                        }
+                    }
+                    if self.config.rps_tick_seconds > 0 {
+                        if self.rate.gauge_id.is_none() {
+                            self.rate.gauge_id = proxy_wasm::hostcalls::define_metric(MetricType::Gauge, "marchproxy_requests_per_second").ok();
+                        }
+                    } else {
+                        self.on_done();
+                        self.rate.last_tick_ns = 0;
+                    }
+                    // The rate gauge copes with any tick period, so a summary shares its timer
+                    let tick_seconds = if self.config.rps_tick_seconds > 0 {
+                        self.config.rps_tick_seconds
+                    } else if self.config.latency_metric_type == MetricKind::Summary {
+                        self.config.summary_interval_seconds.max(1)
+                    } else {
+                        0
+                    };
+                    self.set_tick_period(std::time::Duration::from_secs(tick_seconds));
                     proxy_wasm::hostcalls::log(LogLevel::Info, &format!("Metrics filter configured - sample rate: {}", self.config.sample_rate)).ok();
                     true
                 }
@@ -56,17 +644,59 @@ impl RootContext for MetricsFilterRoot {
                 }
             }
         } else {
-            proxy_wasm::hostcalls::log(LogLevel::Info, &format!("No metrics configuration provided, using defaults")).ok();
+            proxy_wasm::hostcalls::log(LogLevel::Info, "No metrics configuration provided, using defaults").ok();
             true
         }
     }
 
-    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+    fn on_tick(&mut self) {
+        enter_hook("on_tick");
+        if self.config.rps_tick_seconds > 0 {
+            self.report_request_rate();
+        }
+        if self.config.latency_metric_type == MetricKind::Summary {
+            self.report_latency_summary();
+        }
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        // Counted before sampling so the rate reflects all traffic
+        self.rate.requests.set(self.rate.requests.get().wrapping_add(1));
+
+        // Mix the context id into the seed so contexts created in the same instant diverge
+        let now = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default().as_nanos() as u64;
+        let mut rng = SplitMix64::new(now ^ (context_id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let warmup = self.claim_warmup_sample();
+        let sampled = warmup || rng.sample(self.config.sample_rate);
+        let scale = sample_scale(&self.config, self.config.sample_rate, sampled && !warmup);
+        let dual_emit = self.config.dual_emit_prefix.is_some() && rng.sample(self.config.dual_emit_rate);
+        // Kept for re-deciding once the license edition header is visible
+        let edition_draw = rng.next_f64();
+
         Some(Box::new(MetricsFilter {
             config: self.config.clone(),
+            registry: self.config.serve_prometheus.then(|| Rc::clone(&self.registry)),
+            stats: Rc::clone(&self.stats),
+            sampled,
+            warmup,
+            edition_draw,
+            dual_emit,
+            scale,
+            scale_carry: Rc::clone(&self.scale_carry),
+            latency_reservoir: (self.config.latency_metric_type == MetricKind::Summary)
+                .then(|| Rc::clone(&self.latency_reservoir)),
+            metrics: MetricsToggle::from_config(&self.config),
+            tenant: None,
+            trace_id: None,
             request_start_time: 0,
+            request_path: String::new(),
+            response_status: 0,
             request_size: 0,
             response_size: 0,
+            inspect_response_body: false,
+            response_body: BodyBuffer::new(self.config.max_buffer_bytes),
+            compression_probe: None,
         }))
     }
 
@@ -75,17 +705,148 @@ impl RootContext for MetricsFilterRoot {
     }
 }
 
+impl MetricsFilterRoot {
+    /// Moves this VM's contribution to `marchproxy_requests_per_second` to the rate seen since
+    /// the previous tick.
+    fn report_request_rate(&mut self) {
+        let now = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default().as_nanos() as u64;
+        let rate = &mut self.rate;
+        if rate.last_tick_ns > 0 && now > rate.last_tick_ns {
+            // A count below the last sample means the counter restarted; count from zero
+            let requests = rate.requests.get();
+            let delta = if requests >= rate.last_requests { requests - rate.last_requests } else { requests };
+            let per_second = (delta as f64 * 1_000_000_000.0 / (now - rate.last_tick_ns) as f64).round() as i64;
+            if let Some(gauge_id) = rate.gauge_id {
+                proxy_wasm::hostcalls::increment_metric(gauge_id, per_second - rate.reported_rate).ok();
+                rate.reported_rate = per_second;
+            }
+        }
+        rate.last_requests = rate.requests.get();
+        rate.last_tick_ns = now;
+    }
+
+    /// Publishes this window's latency quantiles as `marchproxy_request_duration_<unit>_p<N>`
+    /// gauges, at `histogram_percentiles` or p50/p90/p99. Quantiles cannot be merged across
+    /// workers, so each gauge holds the value from the worker that reported last.
+    fn report_latency_summary(&mut self) {
+        let percentiles: &[f64] = if self.config.histogram_percentiles.is_empty() {
+            &DEFAULT_SUMMARY_PERCENTILES
+        } else {
+            &self.config.histogram_percentiles
+        };
+        let quantiles = match self.latency_reservoir.borrow_mut().drain_percentiles(percentiles) {
+            Some(quantiles) => quantiles,
+            None => return,
+        };
+        for (percentile, value) in quantiles {
+            let name = format!("marchproxy_request_duration_{}_p{}", self.config.duration_unit.stat_suffix(), percentile.to_string().replace('.', "_"));
+            let gauge_id = match self.summary_gauges.get(&name) {
+                Some(gauge_id) => *gauge_id,
+                None => match proxy_wasm::hostcalls::define_metric(MetricType::Gauge, &name) {
+                    Ok(gauge_id) => *self.summary_gauges.entry(name).or_insert(gauge_id),
+                    Err(_) => continue,
+                },
+            };
+            proxy_wasm::hostcalls::record_metric(gauge_id, value).ok();
+        }
+    }
+
+    /// Takes one slot from the shared warmup counter, returning true while the first
+    /// `warmup_sample_count` requests (across all VMs) are still being captured.
+    fn claim_warmup_sample(&self) -> bool {
+        if self.config.warmup_sample_count == 0 {
+            return false;
+        }
+
+        // Retry a few times on CAS conflicts with other workers, then give up on this request
+        for _ in 0..3 {
+            let (data, cas) = self.get_shared_data(WARMUP_COUNTER_KEY);
+            let count = data
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u64::from_le_bytes)
+                .unwrap_or(0);
+            if count >= self.config.warmup_sample_count {
+                return false;
+            }
+            if self.set_shared_data(WARMUP_COUNTER_KEY, Some(&(count + 1).to_le_bytes()), cas).is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
 struct MetricsFilter {
     config: FilterConfig,
+    registry: Option<Rc<RefCell<prometheus::Registry>>>,
+    stats: Rc<RefCell<stats::EnvoyStats>>,
+    sampled: bool,
+    /// Sampled as one of the first `warmup_sample_count` requests, regardless of rate.
+    warmup: bool,
+    /// Uniform draw in `[0, 1)` compared against the edition's sample rate.
+    edition_draw: f64,
+    dual_emit: bool,
+    /// Weight of each counter increment: `1 / sample_rate` with `scale_by_sample_rate`, else 1.
+    scale: f64,
+    scale_carry: Rc<RefCell<HashMap<String, f64>>>,
+    /// Set when latency is recorded as a summary instead of a histogram.
+    latency_reservoir: Option<Rc<RefCell<Reservoir>>>,
+    metrics: MetricsToggle,
+    /// Metric label of the request's tenant, with `tenant_from` set.
+    tenant: Option<String>,
+    /// W3C trace id from `traceparent`, with `enable_exemplars` set.
+    trace_id: Option<String>,
     request_start_time: u64,
+    request_path: String,
+    response_status: u32,
     request_size: usize,
     response_size: usize,
+    inspect_response_body: bool,
+    response_body: BodyBuffer,
+    compression_probe: Option<CompressionProbe>,
 }
 
 impl Context for MetricsFilter {}
 
 impl HttpContext for MetricsFilter {
     fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        enter_hook("on_http_request_headers");
+        check_filter_position("metrics", self.config.expected_position);
+        if let Some(action) = self.serve_config_dump() {
+            // Like scrapes, dumps are not counted
+            self.sampled = false;
+            return action;
+        }
+
+        if let Some(registry) = &self.registry {
+            let path = self.get_http_request_header(":path").unwrap_or_default();
+            if path.split('?').next() == Some(self.config.metrics_path.as_str()) {
+                // Scrapes are not counted in the metrics they report
+                self.sampled = false;
+                let body = registry.borrow().render(&self.config.histogram_percentiles);
+                self.send_http_response(
+                    200,
+                    vec![("content-type", "text/plain; version=0.0.4")],
+                    Some(body.as_bytes()),
+                );
+                return Action::Pause;
+            }
+        }
+
+        // Must override the sampling decision before any hook consults it
+        if let Some(rate) = self.edition_sample_rate() {
+            let drawn = rate >= 1.0 || self.edition_draw < rate as f64;
+            self.sampled = self.warmup || drawn;
+            self.scale = sample_scale(&self.config, rate, drawn && !self.warmup);
+        }
+        // Checked even for sampled requests, so a secret-bearing header is always stripped
+        if self.is_force_sampled() && !self.sampled {
+            proxy_wasm::hostcalls::log(LogLevel::Info, "Sampling forced by force-sample header").ok();
+            self.increment_metric("marchproxy_forced_samples_total", 1);
+            self.sampled = true;
+        }
+
         // Record request start time
         self.request_start_time = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_default().as_nanos() as u64;
@@ -95,20 +856,38 @@ impl HttpContext for MetricsFilter {
             return Action::Continue;
         }
 
-        if self.config.enable_request_metrics {
+        if !self.config.metrics_route_rules.is_empty() {
+            let path = self.get_http_request_header(":path").unwrap_or_default();
+            self.metrics = MetricsToggle::for_path(&self.config, &path);
+        }
+
+        self.tenant = self.tenant_label();
+        if self.config.enable_exemplars {
+            self.trace_id = self.get_http_request_header("traceparent")
+                .and_then(|traceparent| parse_trace_id(&traceparent));
+        }
+
+        if self.config.slo_latency_threshold_ms.is_some() || !self.config.slo_routes.is_empty() || self.config.enable_retry_metrics {
+            self.request_path = self.get_http_request_header(":path").unwrap_or_default();
+        }
+
+        if self.metrics.request {
             // Get request details
-            let method = self.get_http_request_header(":method").unwrap_or_default();
+            let method = self.effective_method();
             let path = self.get_http_request_header(":path").unwrap_or_default();
             let host = self.get_http_request_header(":authority").unwrap_or_default();
-            let user_agent = self.get_http_request_header("user-agent").unwrap_or_default();
 
             // Increment request counter
             self.increment_metric("marchproxy_requests_total", 1);
 
             // Record request by method
-            let metric_name = format!("marchproxy_requests_by_method_{}", method.to_lowercase());
+            let metric_name = format!("marchproxy_requests_by_method_{}", method_label(&method));
             self.increment_metric(&metric_name, 1);
 
+            if let Some(tenant) = &self.tenant {
+                self.increment_metric(&format!("marchproxy_requests_by_tenant_{}", tenant), 1);
+            }
+
             // Record request by path (sanitized)
             let path_prefix = self.get_path_prefix(&path);
             let metric_name = format!("marchproxy_requests_by_path_{}", path_prefix);
@@ -117,25 +896,58 @@ impl HttpContext for MetricsFilter {
             proxy_wasm::hostcalls::log(LogLevel::Debug, &format!("Request: {} {} from {}", method, path, host)).ok();
         }
 
+        if self.config.enable_protocol_metrics {
+            let protocol = self.get_property(vec!["request", "protocol"])
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .unwrap_or_default();
+            // Fixed label set keeps cardinality bounded
+            let label = match protocol.as_str() {
+                "HTTP/1.0" => "http1_0",
+                "HTTP/1.1" => "http1_1",
+                "HTTP/2" => "http2",
+                "HTTP/3" => "http3",
+                _ => "other",
+            };
+            self.increment_metric(&format!("marchproxy_requests_by_protocol_{}", label), 1);
+        }
+
+        if !self.config.capture_headers.is_empty() {
+            let headers = self.get_http_request_headers();
+            proxy_wasm::hostcalls::log(LogLevel::Info, &format!("Captured request headers: {}", self.capture_headers(&headers))).ok();
+        }
+
+        // Observe-only: count requests lacking headers we expect clients to send
+        for header in &self.config.required_headers_metric {
+            if self.get_http_request_header(header).is_none() {
+                let metric_name = format!("marchproxy_missing_header_{}", header.to_lowercase().replace('-', "_"));
+                self.increment_metric(&metric_name, 1);
+            }
+        }
+
         Action::Continue
     }
 
     fn on_http_request_body(&mut self, body_size: usize, _end_of_stream: bool) -> Action {
-        if self.config.enable_size_metrics && self.should_sample() {
+        enter_hook("on_http_request_body");
+        if self.metrics.size && self.should_sample() {
             self.request_size += body_size;
         }
         Action::Continue
     }
 
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        enter_hook("on_http_response_headers");
         if !self.should_sample() {
             return Action::Continue;
         }
 
-        if self.config.enable_response_metrics {
-            // Get response status
-            let status = self.get_http_response_header(":status").unwrap_or_default();
-            let status_code: u32 = status.parse().unwrap_or(0);
+        // Get response status
+        let status = self.get_http_response_header(":status").unwrap_or_default();
+        let status_code: u32 = status.parse().unwrap_or(0);
+        self.response_status = status_code;
+        let version = self.canary_version();
+
+        if self.metrics.response {
 
             // Increment response counter
             self.increment_metric("marchproxy_responses_total", 1);
@@ -149,38 +961,143 @@ impl HttpContext for MetricsFilter {
             let metric_name = format!("marchproxy_responses_by_class_{}xx", status_class);
             self.increment_metric(&metric_name, 1);
 
+            if let Some(version) = &version {
+                self.increment_metric(&format!("marchproxy_responses_by_version_{}", version), 1);
+                self.increment_metric(&format!("marchproxy_responses_by_version_{}_class_{}xx", version, status_class), 1);
+            }
+            if let Some(tenant) = &self.tenant {
+                self.increment_metric(&format!("marchproxy_responses_by_tenant_{}_class_{}xx", tenant, status_class), 1);
+            }
+
             proxy_wasm::hostcalls::log(LogLevel::Debug, &format!("Response: {}", status_code)).ok();
         }
 
-        if self.config.enable_timing_metrics {
-            // Calculate request duration
-            let now = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default().as_nanos() as u64;
-            let duration_ns = now - self.request_start_time;
-            let duration_ms = duration_ns as f64 / 1_000_000.0;
+        if self.config.enable_retry_metrics {
+            self.record_attempts();
+        }
+
+        if !self.config.capture_headers.is_empty() {
+            let headers = self.get_http_response_headers();
+            proxy_wasm::hostcalls::log(LogLevel::Info, &format!("Captured response headers: {}", self.capture_headers(&headers))).ok();
+        }
+
+        if self.config.enable_body_error_detection {
+            self.inspect_response_body = self.is_inspectable_response();
+        }
+
+        if self.config.enable_compression_ratio_metrics {
+            let encoding = self.get_http_response_header("content-encoding").unwrap_or_default();
+            if encoding.trim().eq_ignore_ascii_case("gzip") {
+                self.compression_probe = Some(CompressionProbe::new(self.config.max_decompressed_bytes));
+            }
+        }
+
+        if self.metrics.timing {
+            // Calculate request duration; timing is the last block, so a bad clock just ends the hook
+            let duration_ns = match self.elapsed_ns() {
+                Some(duration_ns) => duration_ns,
+                None => {
+                    proxy_wasm::hostcalls::log(LogLevel::Warn, "Host clock moved backwards during the request, skipping its duration").ok();
+                    self.increment_metric("marchproxy_clock_skew_events_total", 1);
+                    return Action::Continue;
+                }
+            };
+            let unit = self.config.duration_unit;
+            let duration = unit.convert(duration_ns);
+            let recorded = unit.recorded(duration_ns);
 
             // Record latency histogram
-            self.record_metric("marchproxy_request_duration_ms", duration_ms as u64);
+            match &self.latency_reservoir {
+                Some(reservoir) => reservoir.borrow_mut().observe(recorded),
+                None => self.record_duration("", recorded),
+            }
+            // Envoy stats cannot carry exemplars, so the sample is linked to its trace in the log
+            if let Some(trace_id) = &self.trace_id {
+                proxy_wasm::hostcalls::log(
+                    LogLevel::Info,
+                    &format!("Exemplar: marchproxy_request_duration_{} le={} value={:.2} trace_id={}",
+                        unit.suffix(), prometheus::bucket_label(recorded, unit.divisor()), duration, trace_id),
+                ).ok();
+            }
+            if let Some(version) = &version {
+                self.record_duration(&format!("_by_version_{}", version), recorded);
+            }
+            if let Some(tenant) = &self.tenant {
+                self.record_duration(&format!("_by_tenant_{}", tenant), recorded);
+            }
+
+            // Keep fast failures from skewing success latency; only 2xx-5xx, so four series at most
+            if self.config.duration_by_status_class {
+                let status_class = self.get_http_response_header(":status")
+                    .and_then(|status| status.parse::<u32>().ok())
+                    .map(|status| status / 100)
+                    .filter(|class| (2..=5).contains(class));
+                if let Some(status_class) = status_class {
+                    self.record_duration(&format!("_by_class_{}xx", status_class), recorded);
+                }
+            }
+
+            proxy_wasm::hostcalls::log(LogLevel::Debug, &format!("Request duration: {:.2}{}", duration, unit.suffix())).ok();
 
-            proxy_wasm::hostcalls::log(LogLevel::Debug, &format!("Request duration: {:.2}ms", duration_ms)).ok();
+            // Split upstream time from proxy overhead when Envoy reports it
+            let upstream_ms = self.get_http_response_header("x-envoy-upstream-service-time")
+                .and_then(|value| value.trim().parse::<u64>().ok());
+            if let Some(upstream_ms) = upstream_ms {
+                let total_ms = duration_ns / 1_000_000;
+                self.record_metric("marchproxy_upstream_service_time_ms", upstream_ms);
+                self.record_metric("marchproxy_proxy_overhead_ms", total_ms.saturating_sub(upstream_ms));
+            }
         }
 
         Action::Continue
     }
 
-    fn on_http_response_body(&mut self, body_size: usize, _end_of_stream: bool) -> Action {
-        if self.config.enable_size_metrics && self.should_sample() {
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        enter_hook("on_http_response_body");
+        if self.metrics.size && self.should_sample() {
             self.response_size += body_size;
         }
+
+        if let Some(mut probe) = self.compression_probe.take() {
+            // Each chunk is decompressed and dropped, so only one chunk is held at a time
+            if let Some(chunk) = self.get_http_response_body(0, body_size) {
+                probe.feed(&chunk);
+            }
+            if end_of_stream || probe.is_stopped() {
+                self.record_compression_ratio(probe);
+            } else {
+                self.compression_probe = Some(probe);
+            }
+        }
+
+        if self.inspect_response_body {
+            // Accumulate chunks ourselves so the response keeps streaming to the client
+            let mut buffer = std::mem::take(&mut self.response_body);
+            buffer.append(body_size, |len| self.get_http_response_body(0, len));
+
+            if buffer.truncated() {
+                // A truncated JSON document cannot be parsed, so stop inspecting
+                self.inspect_response_body = false;
+            } else if end_of_stream {
+                self.inspect_response_body = false;
+                if self.is_soft_error(buffer.data()) {
+                    self.increment_metric("marchproxy_soft_error_responses_total", 1);
+                }
+            } else {
+                self.response_body = buffer;
+            }
+        }
+
         Action::Continue
     }
 
     fn on_log(&mut self) {
+        enter_hook("on_log");
         if !self.should_sample() {
             return;
         }
 
-        if self.config.enable_size_metrics {
+        if self.metrics.size {
             // Record request and response sizes
             if self.request_size > 0 {
                 self.record_metric("marchproxy_request_size_bytes", self.request_size as u64);
@@ -195,44 +1112,459 @@ impl HttpContext for MetricsFilter {
                         self.request_size, self.response_size)
             ).ok();
         }
+
+        // Requests that saw the clock move backwards have no usable duration and are left out
+        let apdex_duration_ns = (self.config.enable_apdex && self.request_start_time > 0)
+            .then(|| self.elapsed_ns())
+            .flatten();
+        if let Some(duration_ns) = apdex_duration_ns {
+            // Apdex = (satisfied + tolerating / 2) / total, computed on the dashboard side
+            let duration_ms = duration_ns / 1_000_000;
+            let target_ms = self.config.apdex_target_ms;
+
+            let metric_name = if duration_ms <= target_ms {
+                "marchproxy_apdex_satisfied_total"
+            } else if duration_ms <= target_ms * 4 {
+                "marchproxy_apdex_tolerating_total"
+            } else {
+                "marchproxy_apdex_frustrated_total"
+            };
+            self.increment_metric(metric_name, 1);
+        }
+
+        if self.request_start_time > 0 {
+            self.record_slo_event();
+        }
     }
 }
 
 impl MetricsFilter {
-    fn should_sample(&self) -> bool {
-        if self.config.sample_rate >= 1.0 {
-            return true;
+    /// With `enable_config_dump`, answers a request for `config_dump_path` carrying the
+    /// `x-marchproxy-config-secret` header with the effective config as JSON, secrets redacted.
+    /// Without the right secret the request is handled like any other.
+    fn serve_config_dump(&mut self) -> Option<Action> {
+        if !self.config.enable_config_dump {
+            return None;
+        }
+        let path = self.get_http_request_header(":path")?;
+        if path.split('?').next() != Some(self.config.config_dump_path.as_str()) {
+            return None;
+        }
+        let secret = self.get_http_request_header(CONFIG_DUMP_SECRET_HEADER)?;
+        if !secret_eq(&secret, &self.config.config_dump_secret) {
+            proxy_wasm::hostcalls::log(LogLevel::Warn, "Config dump requested with a wrong secret").ok();
+            return None;
+        }
+
+        let mut config = serde_json::to_value(&self.config).ok()?;
+        redact_secrets(&mut config, &SECRET_FIELDS);
+        proxy_wasm::hostcalls::log(LogLevel::Info, "Serving metrics filter config dump").ok();
+        self.send_http_response(
+            200,
+            vec![("content-type", "application/json"), ("cache-control", "no-store")],
+            Some(config.to_string().as_bytes()),
+        );
+        Some(Action::Pause)
+    }
+
+    /// The request method, or with `trust_method_override` the override the auth filter accepted
+    /// from a trusted source. The auth filter must run first; the header itself is never read
+    /// here, so clients cannot spoof the label.
+    fn effective_method(&self) -> String {
+        self.config.trust_method_override
+            .then(|| self.get_property(vec![EFFECTIVE_METHOD_PROPERTY]))
+            .flatten()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .or_else(|| self.get_http_request_header(":method"))
+            .unwrap_or_default()
+    }
+
+    /// True when `force_sample_header` is present and truthy (`1`, `true`, `yes`, `on`), or,
+    /// with `force_sample_secret` set, carries exactly that secret (compared in constant time).
+    /// With a secret configured the header is removed so it never reaches the upstream.
+    fn is_force_sampled(&self) -> bool {
+        let header = match &self.config.force_sample_header {
+            Some(header) => header,
+            None => return false,
+        };
+        let value = match self.get_http_request_header(header) {
+            Some(value) => value,
+            None => return false,
+        };
+
+        match &self.config.force_sample_secret {
+            Some(secret) => {
+                self.set_http_request_header(header, None);
+                secret_eq(value.trim(), secret)
+            }
+            None => matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"),
+        }
+    }
+
+    /// The sample rate for the license edition the license filter recorded in
+    /// `x-license-edition`, when one is configured for it.
+    fn edition_sample_rate(&self) -> Option<f32> {
+        if self.config.enterprise_sample_rate.is_none() && self.config.community_sample_rate.is_none() {
+            return None;
+        }
+        let edition = self.get_http_request_header("x-license-edition")?;
+        self.config.sample_rate_for_edition(&edition)
+    }
+
+    /// With `enable_canary_metrics`, the backend version from `canary_version_header` as a metric
+    /// label: one of `canary_versions`, else `other`, so upstreams cannot grow cardinality.
+    fn canary_version(&self) -> Option<String> {
+        if !self.config.enable_canary_metrics {
+            return None;
+        }
+        let version = self.get_http_response_header(&self.config.canary_version_header).unwrap_or_default();
+        let version = version.trim();
+        if !self.config.canary_versions.iter().any(|known| known == version) {
+            return Some(String::from("other"));
+        }
+        Some(metric_label(version))
+    }
+
+    /// With `tenant_from` set, the request's tenant as a metric label: one of `known_tenants`
+    /// (matched case-insensitively), else `other`, so clients cannot grow cardinality.
+    fn tenant_label(&self) -> Option<String> {
+        let tenant = match self.config.tenant_from.as_ref()? {
+            TenantSource::Authority => self.get_http_request_header(":authority")
+                .map(|authority| authority_host(&authority).to_string()),
+            TenantSource::Header(name) => self.get_http_request_header(name),
+            TenantSource::Claim(claim) => self.bearer_claim(claim),
+        };
+        Some(known_label(tenant.as_deref().unwrap_or_default(), &self.config.known_tenants))
+    }
+
+    /// A string claim from the bearer JWT's payload. The signature is not checked: a forged
+    /// token can only pick another known tenant's label, and the auth filter should run first.
+    fn bearer_claim(&self, claim: &str) -> Option<String> {
+        let authorization = self.get_http_request_header("authorization")?;
+        let payload = authorization.strip_prefix("Bearer ")?.split('.').nth(1)?;
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+        claims.get(claim)?.as_str().map(String::from)
+    }
+
+    /// Records how many upstream attempts the request took, from the `x-envoy-attempt-count`
+    /// response header. Envoy only adds it with the route's `include_attempt_count_in_response`.
+    fn record_attempts(&self) {
+        let attempts = match self.get_http_response_header("x-envoy-attempt-count")
+            .and_then(|value| value.trim().parse::<u64>().ok())
+        {
+            Some(attempts) => attempts,
+            None => return,
+        };
+        self.record_metric("marchproxy_request_attempts", attempts);
+        if attempts > 1 {
+            self.increment_metric("marchproxy_retried_requests_total", 1);
+            let path_prefix = self.get_path_prefix(&self.request_path);
+            self.increment_metric(&format!("marchproxy_retried_requests_by_path_{}", path_prefix), 1);
         }
+    }
 
-        // Simple sampling: use current time for pseudo-random sampling
+    /// Nanoseconds since the request started; see `elapsed_between`.
+    fn elapsed_ns(&self) -> Option<u64> {
         let now = self.get_current_time().duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default().as_millis() as u64;
-        let sample_threshold = (self.config.sample_rate * 1000.0) as u64;
-        (now % 1000) < sample_threshold
+            .unwrap_or_default().as_nanos() as u64;
+        elapsed_between(self.request_start_time, now)
     }
 
-    fn get_path_prefix(&self, path: &str) -> String {
-        // Extract first path component for grouping
-        let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-        if parts.is_empty() {
-            return "root".to_string();
+    fn should_sample(&self) -> bool {
+        // Decided once per request at context creation so every hook agrees
+        self.sampled
+    }
+
+    /// Counts the request as a good or bad SLO event against the threshold of the longest
+    /// matching SLO route, else the global threshold. Bad means too slow or, with
+    /// `slo_error_is_5xx`, a 5xx (including requests that never got a response).
+    fn record_slo_event(&self) {
+        let route = self.config.slo_routes.iter()
+            .filter(|route| self.request_path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.len());
+        let threshold_ms = match route.map(|route| route.latency_threshold_ms).or(self.config.slo_latency_threshold_ms) {
+            Some(threshold_ms) => threshold_ms,
+            None => return,
+        };
+
+        // A backward clock would pass as a fast response, so such requests are not counted
+        let duration_ms = match self.elapsed_ns() {
+            Some(duration_ns) => duration_ns / 1_000_000,
+            None => return,
+        };
+        let is_error = self.config.slo_error_is_5xx
+            && (self.response_status >= 500 || self.response_status == 0);
+        let outcome = if duration_ms <= threshold_ms && !is_error { "good" } else { "bad" };
+
+        match route {
+            Some(route) => self.increment_metric(&format!("marchproxy_slo_{}_by_route_{}", outcome, route.name), 1),
+            None => self.increment_metric(&format!("marchproxy_slo_{}_total", outcome), 1),
         }
+    }
 
-        // Return first path component, sanitized
-        parts[0].chars()
-            .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
-            .collect()
+    /// Renders the `capture_headers` present in `headers` as `name=value` pairs, masking the
+    /// values of `SENSITIVE_HEADERS`. Only called for sampled requests, bounding log volume.
+    fn capture_headers(&self, headers: &[(String, String)]) -> String {
+        headers.iter()
+            .filter(|(name, _)| self.config.capture_headers.iter().any(|wanted| wanted.eq_ignore_ascii_case(name)))
+            .map(|(name, value)| {
+                let sensitive = SENSITIVE_HEADERS.iter().any(|sensitive| sensitive.eq_ignore_ascii_case(name));
+                format!("{}={}", name, if sensitive { "[REDACTED]" } else { value.as_str() })
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Records the body's decompression ratio and flags it when at or above the threshold,
+    /// which for an untrusted upstream suggests a decompression bomb.
+    fn record_compression_ratio(&self, probe: CompressionProbe) {
+        let ratio = match probe.finish() {
+            Some(ratio) => ratio,
+            None => return,
+        };
+        self.record_metric("marchproxy_response_compression_ratio", ratio);
+        if ratio >= self.config.compression_ratio_threshold {
+            proxy_wasm::hostcalls::log(
+                LogLevel::Warn,
+                &format!("Response body decompresses {}x, above the {}x threshold", ratio, self.config.compression_ratio_threshold),
+            ).ok();
+            self.increment_metric("marchproxy_high_compression_responses_total", 1);
+        }
+    }
+
+    fn is_inspectable_response(&self) -> bool {
+        let content_type = self.get_http_response_header("content-type").unwrap_or_default();
+        if !content_type.to_lowercase().starts_with("application/json") {
+            return false;
+        }
+
+        // Skip bodies that announce themselves as larger than the cap up front
+        match self.get_http_response_header("content-length").and_then(|v| v.parse::<usize>().ok()) {
+            Some(length) => length > 0 && length <= self.config.max_buffer_bytes,
+            None => true,
+        }
+    }
+
+    fn is_soft_error(&self, body: &[u8]) -> bool {
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(serde_json::Value::Object(fields)) => fields
+                .get(&self.config.error_marker_field)
+                .map(|value| !value.is_null())
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    fn get_path_prefix(&self, path: &str) -> String {
+        path_label(path, &self.config.known_path_prefixes)
     }
 
     fn increment_metric(&self, name: &str, value: u64) {
-        // Use Envoy's metric system
-        // Note: In a real implementation, this would use the Envoy stats system
-        // For WASM, we rely on Envoy's built-in metrics collection
-        proxy_wasm::hostcalls::log(LogLevel::Trace, &format!("Metric: {} += {}", name, value)).ok();
+        let value = self.scaled(name, value);
+        if value == 0 {
+            return;
+        }
+        let mut stats = self.stats.borrow_mut();
+        stats.increment(name, value);
+        if let Some(registry) = &self.registry {
+            registry.borrow_mut().increment(name, value);
+        }
+        if let Some(dual_name) = self.dual_metric_name(name) {
+            stats.increment(&dual_name, value);
+        }
+    }
+
+    /// Multiplies a counter increment by `scale`, emitting whole units and carrying the fraction
+    /// for this metric into its next increment on this VM, so the counter tracks the estimate.
+    fn scaled(&self, name: &str, value: u64) -> u64 {
+        if self.scale <= 1.0 {
+            return value;
+        }
+        let mut carry = self.scale_carry.borrow_mut();
+        let pending = carry.entry(name.to_string()).or_insert(0.0);
+        let exact = value as f64 * self.scale + *pending;
+        let whole = exact.floor();
+        *pending = exact - whole;
+        whole as u64
     }
 
     fn record_metric(&self, name: &str, value: u64) {
-        // Record histogram/gauge metric
-        proxy_wasm::hostcalls::log(LogLevel::Trace, &format!("Metric: {} = {}", name, value)).ok();
+        let mut stats = self.stats.borrow_mut();
+        stats.record(name, value);
+        if let Some(registry) = &self.registry {
+            registry.borrow_mut().observe(name, value);
+        }
+        if let Some(dual_name) = self.dual_metric_name(name) {
+            stats.record(&dual_name, value);
+        }
+    }
+
+    /// Records a duration in `marchproxy_request_duration_<unit><qualifier>`: the recorded
+    /// integer in the Envoy stat, and the value in `duration_unit` in the Prometheus output.
+    fn record_duration(&self, qualifier: &str, recorded: u64) {
+        let unit = self.config.duration_unit;
+        let stat_name = format!("marchproxy_request_duration_{}{}", unit.stat_suffix(), qualifier);
+        let mut stats = self.stats.borrow_mut();
+        stats.record(&stat_name, recorded);
+        if let Some(dual_name) = self.dual_metric_name(&stat_name) {
+            stats.record(&dual_name, recorded);
+        }
+        if let Some(registry) = &self.registry {
+            let name = format!("marchproxy_request_duration_{}{}", unit.suffix(), qualifier);
+            registry.borrow_mut().observe_scaled(&name, recorded, unit.divisor());
+        }
+    }
+
+    /// The metric name under the dual-emit prefix (which replaces `marchproxy_`), when this
+    /// request falls in the dual-emit fraction.
+    fn dual_metric_name(&self, name: &str) -> Option<String> {
+        if !self.dual_emit {
+            return None;
+        }
+        let prefix = self.config.dual_emit_prefix.as_ref()?;
+        Some(format!("{}{}", prefix, name.strip_prefix("marchproxy_").unwrap_or(name)))
+    }
+}
+
+/// Config fields holding secrets. The config dump replaces them wherever they occur.
+const SECRET_FIELDS: [&str; 2] = ["force_sample_secret", "config_dump_secret"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seconds_are_reported_in_seconds() {
+        let unit = DurationUnit::Seconds;
+        assert_eq!(unit.convert(250_000_000), 0.25);
+
+        // Envoy stats hold integers, so they keep milliseconds and say so in the name
+        assert_eq!(unit.recorded(250_000_000), 250);
+        assert_eq!(unit.stat_suffix(), "ms");
+
+        let mut registry = prometheus::Registry::new(10);
+        let name = format!("marchproxy_request_duration_{}", unit.suffix());
+        registry.observe_scaled(&name, unit.recorded(250_000_000), unit.divisor());
+        registry.observe_scaled(&name, unit.recorded(1_500_000_000), unit.divisor());
+        let rendered = registry.render(&[]);
+        assert!(rendered.contains("marchproxy_request_duration_seconds_bucket{le=\"0.25\"} 1"));
+        assert!(rendered.contains("marchproxy_request_duration_seconds_bucket{le=\"2.5\"} 2"));
+        assert!(rendered.contains("marchproxy_request_duration_seconds_sum 1.75"));
+    }
+
+    #[test]
+    fn recorded_durations_use_whole_units() {
+        assert_eq!(DurationUnit::Milliseconds.recorded(1_999_999), 1);
+        assert_eq!(DurationUnit::Microseconds.recorded(1_999_999), 1_999);
+        assert_eq!(DurationUnit::Milliseconds.recorded(999_999), 0);
+    }
+
+    #[test]
+    fn backward_clock_yields_no_duration() {
+        assert_eq!(elapsed_between(1_000, 251_000), Some(250_000));
+        assert_eq!(elapsed_between(1_000, 1_000), Some(0));
+        // A clock stepped back by 1ns must not wrap into a ~584 year duration
+        assert_eq!(elapsed_between(1_000, 999), None);
+        assert_eq!(elapsed_between(u64::MAX, 0), None);
+    }
+
+    #[test]
+    fn method_label_folds_unknown_methods() {
+        assert_eq!(method_label("GET"), "get");
+        assert_eq!(method_label("patch"), "patch");
+        assert_eq!(method_label("X-RANDOM-1234"), "other");
+    }
+
+    #[test]
+    fn known_tenants_get_their_own_label() {
+        let known = vec![String::from("Acme"), String::from("globex.example.com"), String::from("::1")];
+        assert_eq!(known_label("acme", &known), "acme");
+        assert_eq!(known_label(" ACME ", &known), "acme");
+        assert_eq!(known_label(authority_host("globex.example.com:8443"), &known), "globex_example_com");
+        // IPv6 authorities keep their address once the port is stripped
+        assert_eq!(known_label(authority_host("[::1]:8080"), &known), "__1");
+    }
+
+    #[test]
+    fn unknown_tenants_are_other() {
+        let known = vec![String::from("acme")];
+        assert_eq!(known_label("initech", &known), "other");
+        assert_eq!(known_label("", &known), "other");
+        assert_eq!(known_label("acme", &[]), "other");
+        assert_eq!(known_label(authority_host("[::1]:8080"), &known), "other");
+    }
+
+    #[test]
+    fn path_label_uses_first_segment() {
+        assert_eq!(path_label("/", &[]), "root");
+        assert_eq!(path_label("/api/v1/users?id=1", &[]), "api");
+        assert_eq!(path_label("/api?x=1", &[]), "api");
+    }
+
+    #[test]
+    fn path_label_folds_unknown_prefixes() {
+        let known = vec![String::from("/api"), String::from("static")];
+        assert_eq!(path_label("/api/v1", &known), "api");
+        assert_eq!(path_label("/static/app.js", &known), "static");
+        assert_eq!(path_label("/random-4f2a9c/x", &known), "other");
+    }
+
+    #[test]
+    fn sampling_matches_the_configured_rate() {
+        let mut rng = SplitMix64::new(0x5EED);
+        for rate in [0.01f32, 0.1, 0.5, 0.9] {
+            let trials = 100_000;
+            let sampled = (0..trials).filter(|_| rng.sample(rate)).count();
+            let observed = sampled as f64 / trials as f64;
+            assert!((observed - rate as f64).abs() < 0.01, "rate {} sampled at {}", rate, observed);
+        }
+    }
+
+    #[test]
+    fn route_rule_disables_timing_but_keeps_counters() {
+        let config = FilterConfig {
+            metrics_route_rules: serde_json::from_value(serde_json::json!([
+                {"prefix": "/health", "timing": false},
+                {"prefix": "/health/deep", "size": false},
+            ])).unwrap(),
+            ..FilterConfig::default()
+        };
+
+        let health = MetricsToggle::for_path(&config, "/health/live");
+        assert!(!health.timing);
+        assert!(health.request && health.response && health.size);
+
+        // The longest prefix wins outright, so its unset fields fall back to the global flags
+        let deep = MetricsToggle::for_path(&config, "/health/deep?full=1");
+        assert!(deep.timing && !deep.size);
+
+        let other = MetricsToggle::for_path(&config, "/api/users");
+        assert!(other.request && other.response && other.timing && other.size);
+    }
+
+    #[test]
+    fn editions_get_their_own_sample_rate() {
+        let config = FilterConfig {
+            sample_rate: 0.5,
+            enterprise_sample_rate: Some(1.0),
+            community_sample_rate: Some(0.05),
+            ..FilterConfig::default()
+        };
+        assert_eq!(config.sample_rate_for_edition("enterprise"), Some(1.0));
+        assert_eq!(config.sample_rate_for_edition("community"), Some(0.05));
+        assert_eq!(config.sample_rate_for_edition("Enterprise"), None);
+        assert_eq!(config.sample_rate_for_edition(""), None);
+
+        // An edition without its own rate keeps the global one
+        let community_only = FilterConfig { community_sample_rate: Some(0.05), ..FilterConfig::default() };
+        assert_eq!(community_only.sample_rate_for_edition("enterprise"), None);
+    }
+
+    #[test]
+    fn sampling_edges_are_exact() {
+        let mut rng = SplitMix64::new(42);
+        assert!((0..1000).all(|_| rng.sample(1.0)));
+        assert!(!(0..1000).any(|_| rng.sample(0.0)));
     }
 }
@@ -0,0 +1,177 @@
+// In-filter metric registry rendered in the Prometheus text exposition format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Upper bounds shared by every histogram; values are in whatever unit the metric records.
+const BUCKET_BOUNDS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// The `le` label of the smallest bucket holding `value`, for pointing at a bucket from logs.
+/// `divisor` is the one the histogram was observed with.
+pub fn bucket_label(value: u64, divisor: u64) -> String {
+    BUCKET_BOUNDS.iter()
+        .find(|bound| value <= **bound)
+        .map(|bound| scaled(*bound as f64, divisor).to_string())
+        .unwrap_or_else(|| String::from("+Inf"))
+}
+
+/// `value` divided by `divisor`, e.g. milliseconds rendered as seconds.
+fn scaled(value: f64, divisor: u64) -> f64 {
+    value / divisor.max(1) as f64
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKET_BOUNDS.len()],
+    sum: u64,
+    count: u64,
+    /// Rendered bounds, sum and percentiles are divided by this.
+    divisor: u64,
+}
+
+/// Counters and histograms accumulated by this VM. Each Envoy worker runs its own VM, so a
+/// scrape reflects the requests handled by the worker that served it. At most `max_series`
+/// distinct metrics are kept; updates to further ones are dropped and counted, so label values
+/// that slip past normalisation cannot grow the registry without bound.
+pub struct Registry {
+    counters: BTreeMap<String, u64>,
+    histograms: BTreeMap<String, Histogram>,
+    max_series: usize,
+    dropped: u64,
+}
+
+/// Name of the counter reporting updates dropped by the series cap.
+pub const DROPPED_SERIES_METRIC: &str = "marchproxy_metrics_series_dropped_total";
+
+impl Registry {
+    pub fn new(max_series: usize) -> Self {
+        Self { counters: BTreeMap::new(), histograms: BTreeMap::new(), max_series, dropped: 0 }
+    }
+
+    pub fn set_max_series(&mut self, max_series: usize) {
+        self.max_series = max_series;
+    }
+
+    fn is_full(&self) -> bool {
+        self.counters.len() + self.histograms.len() >= self.max_series
+    }
+
+    pub fn increment(&mut self, name: &str, value: u64) {
+        let name = metric_name(name);
+        if !self.counters.contains_key(&name) && self.is_full() {
+            self.dropped += 1;
+            return;
+        }
+        *self.counters.entry(name).or_insert(0) += value;
+    }
+
+    pub fn observe(&mut self, name: &str, value: u64) {
+        self.observe_scaled(name, value, 1);
+    }
+
+    /// Like `observe`, but the histogram is rendered with its bounds, sum and percentiles
+    /// divided by `divisor`, e.g. 1000 to report values recorded in milliseconds as seconds.
+    pub fn observe_scaled(&mut self, name: &str, value: u64, divisor: u64) {
+        let name = metric_name(name);
+        if !self.histograms.contains_key(&name) && self.is_full() {
+            self.dropped += 1;
+            return;
+        }
+        let histogram = self.histograms.entry(name).or_default();
+        histogram.divisor = divisor;
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKET_BOUNDS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    /// Renders every metric; each histogram is followed by a `<name>_percentile` gauge per
+    /// entry of `percentiles`, estimated from its buckets.
+    pub fn render(&self, percentiles: &[f64]) -> String {
+        let mut out = String::new();
+        for (name, value) in &self.counters {
+            writeln!(out, "# TYPE {} counter", name).ok();
+            writeln!(out, "{} {}", name, value).ok();
+        }
+        writeln!(out, "# TYPE {} counter", DROPPED_SERIES_METRIC).ok();
+        writeln!(out, "{} {}", DROPPED_SERIES_METRIC, self.dropped).ok();
+        for (name, histogram) in &self.histograms {
+            writeln!(out, "# TYPE {} histogram", name).ok();
+            for (bucket, bound) in histogram.buckets.iter().zip(BUCKET_BOUNDS) {
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, scaled(bound as f64, histogram.divisor), bucket).ok();
+            }
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count).ok();
+            writeln!(out, "{}_sum {}", name, scaled(histogram.sum as f64, histogram.divisor)).ok();
+            writeln!(out, "{}_count {}", name, histogram.count).ok();
+
+            if !percentiles.is_empty() && histogram.count > 0 {
+                writeln!(out, "# TYPE {}_percentile gauge", name).ok();
+                for percentile in percentiles {
+                    writeln!(out, "{}_percentile{{percentile=\"{}\"}} {}", name, percentile, scaled(histogram.estimate(*percentile), histogram.divisor)).ok();
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Histogram {
+    /// Estimates a percentile by linear interpolation within the bucket containing it, as
+    /// Prometheus' `histogram_quantile` does. Values past the last bound report that bound.
+    fn estimate(&self, percentile: f64) -> f64 {
+        let rank = percentile / 100.0 * self.count as f64;
+        let mut lower = 0;
+        let mut below = 0;
+        for (&cumulative, bound) in self.buckets.iter().zip(BUCKET_BOUNDS) {
+            if cumulative as f64 >= rank {
+                let in_bucket = (cumulative - below) as f64;
+                let fraction = if in_bucket > 0.0 { (rank - below as f64) / in_bucket } else { 1.0 };
+                return lower as f64 + (bound - lower) as f64 * fraction;
+            }
+            lower = bound;
+            below = cumulative;
+        }
+        lower as f64
+    }
+}
+
+/// Replaces characters Prometheus does not allow in metric names (e.g. `-` from path labels).
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_distinct_series() {
+        let mut registry = Registry::new(2);
+        registry.increment("marchproxy_requests_by_path_api", 1);
+        registry.observe("marchproxy_request_duration_ms", 12);
+        registry.increment("marchproxy_requests_by_path_attacker1", 1);
+        registry.observe("marchproxy_request_duration_ms_by_tenant_x", 3);
+
+        let rendered = registry.render(&[]);
+        assert!(!rendered.contains("attacker1"));
+        assert!(!rendered.contains("by_tenant_x"));
+        assert!(rendered.contains("marchproxy_metrics_series_dropped_total 2"));
+    }
+
+    #[test]
+    fn existing_series_keep_updating_when_full() {
+        let mut registry = Registry::new(1);
+        registry.increment("marchproxy_requests_total", 1);
+        registry.increment("marchproxy_requests_by_path_new", 1);
+        registry.increment("marchproxy_requests_total", 4);
+
+        let rendered = registry.render(&[]);
+        assert!(rendered.contains("marchproxy_requests_total 5"));
+        assert!(rendered.contains("marchproxy_metrics_series_dropped_total 1"));
+    }
+}
@@ -0,0 +1,109 @@
+// Envoy stats emission with a bounded number of metric names
+
+use crate::prometheus::DROPPED_SERIES_METRIC;
+use proxy_wasm::types::{MetricType, Status};
+use std::collections::HashMap;
+
+/// Envoy metric ids defined by this VM, by name. Envoy keeps every stat it is asked to define
+/// for the life of the proxy, so only `max_series` names are defined; updates to further ones
+/// are dropped and counted in `marchproxy_metrics_series_dropped_total`, as the Prometheus
+/// registry does.
+pub struct EnvoyStats {
+    ids: HashMap<String, u32>,
+    max_series: usize,
+}
+
+impl EnvoyStats {
+    pub fn new(max_series: usize) -> Self {
+        Self { ids: HashMap::new(), max_series }
+    }
+
+    pub fn set_max_series(&mut self, max_series: usize) {
+        self.max_series = max_series;
+    }
+
+    pub fn increment(&mut self, name: &str, value: u64) {
+        if let Some(metric_id) = self.metric_id(MetricType::Counter, name, proxy_wasm::hostcalls::define_metric) {
+            proxy_wasm::hostcalls::increment_metric(metric_id, value as i64).ok();
+        }
+    }
+
+    pub fn record(&mut self, name: &str, value: u64) {
+        if let Some(metric_id) = self.metric_id(MetricType::Histogram, name, proxy_wasm::hostcalls::define_metric) {
+            proxy_wasm::hostcalls::record_metric(metric_id, value).ok();
+        }
+    }
+
+    /// The id of `name`, defined through `define` on first use. None when the cap is reached,
+    /// after counting the drop, or when Envoy refuses the definition.
+    fn metric_id(
+        &mut self,
+        metric_type: MetricType,
+        name: &str,
+        define: impl Fn(MetricType, &str) -> Result<u32, Status>,
+    ) -> Option<u32> {
+        if let Some(metric_id) = self.ids.get(name) {
+            return Some(*metric_id);
+        }
+        if self.ids.len() >= self.max_series {
+            if let Ok(dropped_id) = define(MetricType::Counter, DROPPED_SERIES_METRIC) {
+                proxy_wasm::hostcalls::increment_metric(dropped_id, 1).ok();
+            }
+            return None;
+        }
+        match define(metric_type, name) {
+            Ok(metric_id) => {
+                self.ids.insert(name.to_string(), metric_id);
+                Some(metric_id)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn defines_each_name_once() {
+        let mut stats = EnvoyStats::new(10);
+        let defined = RefCell::new(Vec::new());
+        let define = |_: MetricType, name: &str| {
+            defined.borrow_mut().push(name.to_string());
+            Ok(defined.borrow().len() as u32)
+        };
+
+        assert_eq!(stats.metric_id(MetricType::Counter, "marchproxy_requests_total", define), Some(1));
+        assert_eq!(stats.metric_id(MetricType::Counter, "marchproxy_requests_total", define), Some(1));
+        assert_eq!(stats.metric_id(MetricType::Histogram, "marchproxy_request_duration_ms", define), Some(2));
+        assert_eq!(*defined.borrow(), ["marchproxy_requests_total", "marchproxy_request_duration_ms"]);
+    }
+
+    #[test]
+    fn caps_distinct_names() {
+        let mut stats = EnvoyStats::new(2);
+        let defined = RefCell::new(Vec::new());
+        let define = |_: MetricType, name: &str| {
+            defined.borrow_mut().push(name.to_string());
+            Ok(defined.borrow().len() as u32)
+        };
+
+        assert!(stats.metric_id(MetricType::Counter, "marchproxy_requests_by_path_api", define).is_some());
+        assert!(stats.metric_id(MetricType::Counter, "marchproxy_requests_total", define).is_some());
+        assert_eq!(stats.metric_id(MetricType::Counter, "marchproxy_requests_by_path_attacker1", define), None);
+        assert!(stats.metric_id(MetricType::Counter, "marchproxy_requests_total", define).is_some());
+
+        let defined = defined.borrow();
+        assert!(!defined.iter().any(|name| name.contains("attacker1")));
+        assert_eq!(defined.last().map(String::as_str), Some(DROPPED_SERIES_METRIC));
+    }
+
+    #[test]
+    fn refused_definitions_are_retried() {
+        let mut stats = EnvoyStats::new(10);
+        assert_eq!(stats.metric_id(MetricType::Counter, "marchproxy_x", |_, _| Err(Status::InternalFailure)), None);
+        assert_eq!(stats.metric_id(MetricType::Counter, "marchproxy_x", |_, _| Ok(7)), Some(7));
+    }
+}
@@ -27,7 +27,7 @@ fi
 mkdir -p "$OUTPUT_DIR"
 
 # Build each filter
-FILTERS=("auth_filter" "license_filter" "metrics_filter")
+FILTERS=("auth_filter" "license_filter" "metrics_filter" "header_filter" "concurrency_filter")
 
 for filter in "${FILTERS[@]}"; do
     echo ""
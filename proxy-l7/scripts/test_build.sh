@@ -66,7 +66,7 @@ fi
 # Test 3: Check WASM filters
 echo ""
 echo "Test 3: WASM filters"
-FILTERS=("auth_filter" "license_filter" "metrics_filter")
+FILTERS=("auth_filter" "license_filter" "metrics_filter" "header_filter" "concurrency_filter")
 WASM_OK=0
 
 for filter in "${FILTERS[@]}"; do
@@ -92,10 +92,10 @@ for filter in "${FILTERS[@]}"; do
     fi
 done
 
-if [ $WASM_OK -eq 3 ]; then
+if [ $WASM_OK -eq ${#FILTERS[@]} ]; then
     success "All WASM filters built successfully"
 else
-    error "Missing WASM filters (found $WASM_OK/3)"
+    error "Missing WASM filters (found $WASM_OK/${#FILTERS[@]})"
     echo "  Run: ./scripts/build_filters.sh"
 fi
 
@@ -199,7 +199,7 @@ echo " Build Verification Summary"
 echo "═══════════════════════════════════════════════════════════"
 
 # Count components
-TOTAL_COMPONENTS=4
+TOTAL_COMPONENTS=6
 BUILT_COMPONENTS=0
 
 if [ -f "$BUILD_DIR/envoy_xdp.o" ]; then
@@ -214,6 +214,8 @@ echo "  - XDP program: $([ -f "$BUILD_DIR/envoy_xdp.o" ] && echo "✓" || echo "
 echo "  - Auth filter: $([ -f "$BUILD_DIR/auth_filter.wasm" ] && echo "✓" || echo "✗")"
 echo "  - License filter: $([ -f "$BUILD_DIR/license_filter.wasm" ] && echo "✓" || echo "✗")"
 echo "  - Metrics filter: $([ -f "$BUILD_DIR/metrics_filter.wasm" ] && echo "✓" || echo "✗")"
+echo "  - Header filter: $([ -f "$BUILD_DIR/header_filter.wasm" ] && echo "✓" || echo "✗")"
+echo "  - Concurrency filter: $([ -f "$BUILD_DIR/concurrency_filter.wasm" ] && echo "✓" || echo "✗")"
 
 echo ""
 if [ $BUILT_COMPONENTS -eq $TOTAL_COMPONENTS ]; then